@@ -0,0 +1,28 @@
+// Benchmark of the parallel build, run with
+// `cargo bench --features parallel`
+use merkletree::{DefaultHasher, MerkleTree, TreeMode};
+use std::time::Instant;
+
+fn main() {
+    let values: Vec<String> = (0..1 << 21).map(|i| i.to_string()).collect();
+
+    let start = Instant::now();
+    let sequential: MerkleTree =
+        MerkleTree::build_with_mode(&values, DefaultHasher::new(), TreeMode::DEFAULT);
+    let sequential_time = start.elapsed();
+
+    let start = Instant::now();
+    let parallel: MerkleTree =
+        MerkleTree::par_build_with_mode(&values, DefaultHasher::new(), TreeMode::DEFAULT);
+    let parallel_time = start.elapsed();
+
+    assert_eq!(parallel.root_hash(), sequential.root_hash());
+    println!(
+        "{} leaves: sequential {:?}, parallel {:?} on {} threads, {:.1}x faster",
+        values.len(),
+        sequential_time,
+        parallel_time,
+        rayon::current_num_threads(),
+        sequential_time.as_secs_f64() / parallel_time.as_secs_f64()
+    );
+}
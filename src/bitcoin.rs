@@ -0,0 +1,447 @@
+// Bitcoin block merkle trees
+use digest::{
+    Digest, FixedOutput, FixedOutputReset, HashMarker, Output, OutputSizeUser, Reset, Update,
+};
+use sha2::Sha256;
+use std::collections::BTreeSet;
+use std::fmt;
+
+use super::encoding::read_array;
+use super::tree::level_widths;
+use super::{Hash, MerkleError, MerkleTree, TreeMode};
+
+/// SHA-256 applied twice, the hash Bitcoin uses for txids and merkle trees.
+#[derive(Clone, Default)]
+pub struct DoubleSha256(Sha256);
+
+impl DoubleSha256 {
+    pub fn new() -> DoubleSha256 {
+        DoubleSha256(Sha256::default())
+    }
+}
+
+impl HashMarker for DoubleSha256 {}
+
+impl OutputSizeUser for DoubleSha256 {
+    type OutputSize = <Sha256 as OutputSizeUser>::OutputSize;
+}
+
+impl Update for DoubleSha256 {
+    fn update(&mut self, data: &[u8]) {
+        Update::update(&mut self.0, data)
+    }
+}
+
+impl FixedOutput for DoubleSha256 {
+    fn finalize_into(self, out: &mut Output<Self>) {
+        *out = Sha256::digest(self.0.finalize());
+    }
+}
+
+impl Reset for DoubleSha256 {
+    fn reset(&mut self) {
+        Reset::reset(&mut self.0)
+    }
+}
+
+impl FixedOutputReset for DoubleSha256 {
+    fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+        *out = Sha256::digest(self.0.finalize_reset());
+    }
+}
+
+impl fmt::Debug for DoubleSha256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DoubleSha256 {{ Sha256 }}")
+    }
+}
+
+/// Largest number of transactions a block can hold: the block weight limit
+/// divided by the weight of the smallest possible transaction.
+const MAX_TRANSACTIONS: u32 = 4_000_000 / 240;
+
+/// Partial merkle tree of a BIP 37 `merkleblock` message: the hashes and flag
+/// bits proving that some transactions are part of a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialMerkleTree {
+    count_transactions: u32,
+    hashes: Vec<Hash>,
+    bits: Vec<bool>,
+}
+
+impl PartialMerkleTree {
+    /// Builds the partial tree proving the transactions at `positions` of a
+    /// block whose txids `tree` was built over in `TreeMode::BITCOIN`.
+    pub fn build<H>(
+        tree: &MerkleTree<H>,
+        positions: &[usize],
+    ) -> Result<PartialMerkleTree, MerkleError>
+    where
+        H: Digest + FixedOutputReset,
+    {
+        let count_leaves = tree.leaves().len();
+        if count_leaves == 0 || count_leaves > MAX_TRANSACTIONS as usize {
+            return Err(MerkleError::Malformed(
+                "block has no or too many transactions",
+            ));
+        }
+        for &position in positions {
+            if position >= count_leaves {
+                return Err(MerkleError::PositionOutOfRange {
+                    position,
+                    count_leaves,
+                });
+            }
+        }
+
+        let mut partial = PartialMerkleTree {
+            count_transactions: count_leaves as u32,
+            hashes: Vec::new(),
+            bits: Vec::new(),
+        };
+        let matches: BTreeSet<usize> = positions.iter().cloned().collect();
+        let height = level_widths(count_leaves).len() - 1;
+        partial.traverse_and_build(tree, &matches, height, 0);
+
+        Ok(partial)
+    }
+
+    fn traverse_and_build<H>(
+        &mut self,
+        tree: &MerkleTree<H>,
+        matches: &BTreeSet<usize>,
+        height: usize,
+        index: usize,
+    ) where
+        H: Digest + FixedOutputReset,
+    {
+        let first = index << height;
+        let parent_of_match = matches.range(first..first + (1 << height)).next().is_some();
+        self.bits.push(parent_of_match);
+
+        if height == 0 || !parent_of_match {
+            self.hashes.push(tree.node(height, index).as_slice().into());
+        } else {
+            self.traverse_and_build(tree, matches, height - 1, index * 2);
+            if index * 2 + 1 < self.width(height - 1) {
+                self.traverse_and_build(tree, matches, height - 1, index * 2 + 1);
+            }
+        }
+    }
+
+    /// Number of transactions in the block.
+    pub fn count_transactions(&self) -> u32 {
+        self.count_transactions
+    }
+
+    /// Recomputes the merkle root and returns it together with the position
+    /// and txid of every matched transaction.
+    pub fn extract_matches<H>(
+        &self,
+        hasher: &mut H,
+    ) -> Result<(Hash, Vec<(usize, Hash)>), MerkleError>
+    where
+        H: Digest + FixedOutputReset,
+    {
+        if self.count_transactions == 0 || self.count_transactions > MAX_TRANSACTIONS {
+            return Err(MerkleError::Malformed(
+                "block has no or too many transactions",
+            ));
+        }
+        if self.hashes.len() > self.count_transactions as usize {
+            return Err(MerkleError::Malformed("more hashes than transactions"));
+        }
+        if self.bits.len() < self.hashes.len() {
+            return Err(MerkleError::Malformed("fewer flag bits than hashes"));
+        }
+
+        let height = level_widths(self.count_transactions as usize).len() - 1;
+        let mut cursor = Cursor::default();
+        let mut matches = Vec::new();
+        let root = self.traverse_and_extract(height, 0, &mut cursor, &mut matches, hasher)?;
+
+        if cursor.bits.div_ceil(8) != self.bits.len().div_ceil(8) {
+            return Err(MerkleError::Malformed("not all flag bits were consumed"));
+        }
+        if cursor.hashes != self.hashes.len() {
+            return Err(MerkleError::Malformed("not all hashes were consumed"));
+        }
+
+        Ok((root, matches))
+    }
+
+    fn traverse_and_extract<H>(
+        &self,
+        height: usize,
+        index: usize,
+        cursor: &mut Cursor,
+        matches: &mut Vec<(usize, Hash)>,
+        hasher: &mut H,
+    ) -> Result<Hash, MerkleError>
+    where
+        H: Digest + FixedOutputReset,
+    {
+        let parent_of_match = match self.bits.get(cursor.bits) {
+            Some(&bit) => bit,
+            None => return Err(MerkleError::Malformed("ran out of flag bits")),
+        };
+        cursor.bits += 1;
+
+        if height == 0 || !parent_of_match {
+            let hash = match self.hashes.get(cursor.hashes) {
+                Some(hash) => hash.clone(),
+                None => return Err(MerkleError::Malformed("ran out of hashes")),
+            };
+            cursor.hashes += 1;
+            if height == 0 && parent_of_match {
+                matches.push((index, hash.clone()));
+            }
+            return Ok(hash);
+        }
+
+        let left = self.traverse_and_extract(height - 1, index * 2, cursor, matches, hasher)?;
+        let right = if index * 2 + 1 < self.width(height - 1) {
+            let right =
+                self.traverse_and_extract(height - 1, index * 2 + 1, cursor, matches, hasher)?;
+            // A duplicated right node would let two different transaction
+            // lists share this root (CVE-2012-2459).
+            if right == left {
+                return Err(MerkleError::Malformed("identical left and right nodes"));
+            }
+            right
+        } else {
+            left.clone()
+        };
+
+        Ok(TreeMode::BITCOIN
+            .hash_node(&left, &right, hasher)?
+            .as_slice()
+            .into())
+    }
+
+    fn width(&self, height: usize) -> usize {
+        (self.count_transactions as usize).div_ceil(1 << height)
+    }
+
+    /// Encodes the partial tree as in the `merkleblock` message: the number
+    /// of transactions, the hashes and the flag bits packed least significant
+    /// bit first.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(4 + 9 + 32 * self.hashes.len() + 9 + self.bits.len().div_ceil(8));
+        bytes.extend_from_slice(&self.count_transactions.to_le_bytes());
+        write_compact_size(&mut bytes, self.hashes.len() as u64);
+        for hash in &self.hashes {
+            bytes.extend_from_slice(hash);
+        }
+
+        let mut flags = vec![0u8; self.bits.len().div_ceil(8)];
+        for (i, &bit) in self.bits.iter().enumerate() {
+            flags[i / 8] |= (bit as u8) << (i % 8);
+        }
+        write_compact_size(&mut bytes, flags.len() as u64);
+        bytes.extend_from_slice(&flags);
+
+        bytes
+    }
+
+    /// Decodes a partial tree encoded by `to_bytes`. The result still has to
+    /// be checked with `extract_matches`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<PartialMerkleTree, MerkleError> {
+        let mut input = bytes;
+        let count_transactions = u32::from_le_bytes(read_array(&mut input)?);
+
+        let count_hashes = read_compact_size(&mut input)?;
+        if count_hashes > input.len() as u64 / 32 {
+            return Err(MerkleError::Malformed("truncated hashes"));
+        }
+        let mut hashes = Vec::with_capacity(count_hashes as usize);
+        for _ in 0..count_hashes {
+            hashes.push(Hash::from(read_array::<32>(&mut input)?.to_vec()));
+        }
+
+        let count_flags = read_compact_size(&mut input)?;
+        if count_flags > input.len() as u64 {
+            return Err(MerkleError::Malformed("truncated flag bits"));
+        }
+        let (flags, rest) = input.split_at(count_flags as usize);
+        if !rest.is_empty() {
+            return Err(MerkleError::Malformed("trailing bytes"));
+        }
+        let bits = (0..flags.len() * 8)
+            .map(|i| flags[i / 8] >> (i % 8) & 1 == 1)
+            .collect();
+
+        Ok(PartialMerkleTree {
+            count_transactions,
+            hashes,
+            bits,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Cursor {
+    bits: usize,
+    hashes: usize,
+}
+
+fn write_compact_size(bytes: &mut Vec<u8>, n: u64) {
+    if n < 0xfd {
+        bytes.push(n as u8);
+    } else if n <= 0xffff {
+        bytes.push(0xfd);
+        bytes.extend_from_slice(&(n as u16).to_le_bytes());
+    } else if n <= 0xffff_ffff {
+        bytes.push(0xfe);
+        bytes.extend_from_slice(&(n as u32).to_le_bytes());
+    } else {
+        bytes.push(0xff);
+        bytes.extend_from_slice(&n.to_le_bytes());
+    }
+}
+
+fn read_compact_size(input: &mut &[u8]) -> Result<u64, MerkleError> {
+    let [prefix] = read_array(input)?;
+    let (n, min) = match prefix {
+        0xfd => (u16::from_le_bytes(read_array(input)?) as u64, 0xfd),
+        0xfe => (u32::from_le_bytes(read_array(input)?) as u64, 0x1_0000),
+        0xff => (u64::from_le_bytes(read_array(input)?), 0x1_0000_0000),
+        n => (n as u64, 0),
+    };
+    if n < min {
+        return Err(MerkleError::Malformed("non-canonical compact size"));
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DoubleSha256, PartialMerkleTree};
+    use crate::{Hash, MerkleError, MerkleTree, TreeMode};
+
+    /// Parses a txid or merkle root as displayed by block explorers, which
+    /// show hashes in reverse of their internal byte order.
+    fn from_display(hex: &str) -> Hash {
+        let mut hash = hex.parse::<Hash>().unwrap().into_vec();
+        hash.reverse();
+        Hash::from(hash)
+    }
+
+    fn to_display(hash: &[u8]) -> String {
+        let mut hash = hash.to_vec();
+        hash.reverse();
+        Hash::from(hash).to_string()
+    }
+
+    fn block(txids: &[&str]) -> MerkleTree<DoubleSha256> {
+        let leaves: Vec<Hash> = txids.iter().map(|txid| from_display(txid)).collect();
+        MerkleTree::build_from_leaves_with_mode(&leaves, DoubleSha256::new(), TreeMode::BITCOIN)
+    }
+
+    fn txids(count: usize) -> Vec<Hash> {
+        (0..count)
+            .map(|i| {
+                let mut txid = vec![0u8; 32];
+                txid[..8].copy_from_slice(&(i as u64 + 1).to_le_bytes());
+                Hash::from(txid)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bitcoin_mode_reproduces_mainnet_merkle_roots() {
+        // Genesis block
+        let genesis = block(&["4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"]);
+        assert_eq!(
+            to_display(genesis.root_hash()),
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"
+        );
+
+        // Block 170
+        let block_170 = block(&[
+            "b1fea52486ce0c62bb442b530a3f0132b826c74e473d1f2c220bfa78111c5082",
+            "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16",
+        ]);
+        assert_eq!(
+            to_display(block_170.root_hash()),
+            "7dac2c5666815c17a3b36427de37bb9d2e2c5ccec3f8633eb91a4205cb4c10ff"
+        );
+
+        // Block 100000
+        let block_100000 = block(&[
+            "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+            "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+            "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+            "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+        ]);
+        assert_eq!(
+            to_display(block_100000.root_hash()),
+            "f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766"
+        );
+    }
+
+    #[test]
+    fn test_partial_merkle_trees_round_trip_and_extract_matches() {
+        for count in 1..20 {
+            let leaves = txids(count);
+            let tree = MerkleTree::build_from_leaves_with_mode(
+                &leaves,
+                DoubleSha256::new(),
+                TreeMode::BITCOIN,
+            );
+
+            for positions in [
+                vec![],
+                vec![0],
+                vec![count - 1],
+                (0..count).step_by(3).collect(),
+            ] {
+                let partial = PartialMerkleTree::build(&tree, &positions).unwrap();
+                let decoded = PartialMerkleTree::from_bytes(&partial.to_bytes()).unwrap();
+                assert_eq!(decoded.count_transactions(), count as u32);
+
+                let (root, matches) = decoded.extract_matches(&mut DoubleSha256::new()).unwrap();
+                assert_eq!(root.as_bytes(), tree.root_hash());
+                let expected: Vec<(usize, Hash)> =
+                    positions.iter().map(|&i| (i, leaves[i].clone())).collect();
+                assert_eq!(matches, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_partial_merkle_tree_rejects_duplicated_nodes() {
+        let txid = txids(1).remove(0);
+        let partial = PartialMerkleTree {
+            count_transactions: 2,
+            hashes: vec![txid.clone(), txid],
+            bits: vec![true, false, false],
+        };
+
+        assert_eq!(
+            partial.extract_matches(&mut DoubleSha256::new()),
+            Err(MerkleError::Malformed("identical left and right nodes"))
+        );
+    }
+
+    #[test]
+    fn test_partial_merkle_tree_rejects_truncated_and_unused_data() {
+        let tree = MerkleTree::build_from_leaves_with_mode(
+            &txids(5),
+            DoubleSha256::new(),
+            TreeMode::BITCOIN,
+        );
+        let bytes = PartialMerkleTree::build(&tree, &[2]).unwrap().to_bytes();
+        for len in 0..bytes.len() {
+            assert!(PartialMerkleTree::from_bytes(&bytes[..len]).is_err());
+        }
+
+        let mut partial = PartialMerkleTree::build(&tree, &[2]).unwrap();
+        partial.hashes.push(txids(1).remove(0));
+        assert_eq!(
+            partial.extract_matches(&mut DoubleSha256::new()),
+            Err(MerkleError::Malformed("not all hashes were consumed"))
+        );
+    }
+}
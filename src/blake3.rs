@@ -0,0 +1,515 @@
+// BLAKE3 hashing and BLAKE3 file trees
+use ::blake3::hazmat::{self, ChainingValue, HasherExt, Mode};
+use ::blake3::Hasher;
+use digest::consts::U32;
+use digest::{FixedOutput, FixedOutputReset, HashMarker, Output, OutputSizeUser, Reset, Update};
+use std::fmt;
+
+use super::hash::ct_eq;
+use super::tree::level_widths;
+use super::{Hash, MerkleError, MerkleTree, TreeMode};
+
+/// Number of bytes BLAKE3 hashes into one leaf of its tree.
+pub const CHUNK_LEN: usize = 1024;
+
+/// BLAKE3 in its default hashing mode.
+#[derive(Clone, Default)]
+pub struct Blake3(Hasher);
+
+impl Blake3 {
+    pub fn new() -> Blake3 {
+        Blake3(Hasher::new())
+    }
+}
+
+impl HashMarker for Blake3 {}
+
+impl OutputSizeUser for Blake3 {
+    type OutputSize = U32;
+}
+
+impl Update for Blake3 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+}
+
+impl FixedOutput for Blake3 {
+    fn finalize_into(self, out: &mut Output<Self>) {
+        out.copy_from_slice(self.0.finalize().as_bytes());
+    }
+}
+
+impl Reset for Blake3 {
+    fn reset(&mut self) {
+        self.0.reset();
+    }
+}
+
+impl FixedOutputReset for Blake3 {
+    fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+        out.copy_from_slice(self.0.finalize().as_bytes());
+        self.0.reset();
+    }
+}
+
+impl fmt::Debug for Blake3 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Blake3 {{ Hasher }}")
+    }
+}
+
+/// Chaining value of the chunk at `position`. Only the file of a single
+/// chunk may have an empty one, and that chunk is the root, so empty chunks
+/// are rejected along with those longer than `CHUNK_LEN`.
+pub(crate) fn chunk_chaining_value(
+    position: usize,
+    chunk: &[u8],
+) -> Result<ChainingValue, MerkleError> {
+    if chunk.is_empty() || chunk.len() > CHUNK_LEN {
+        return Err(MerkleError::ChunkLengthOutOfRange {
+            position,
+            received: chunk.len(),
+        });
+    }
+
+    let mut hasher = Hasher::new();
+    hasher.set_input_offset((position * CHUNK_LEN) as u64);
+    hasher.update(chunk);
+    Ok(hasher.finalize_non_root())
+}
+
+/// Chaining value of the parent of two chaining values.
+pub(crate) fn parent_chaining_value(
+    left: &[u8],
+    right: &[u8],
+) -> Result<ChainingValue, MerkleError> {
+    let (left, right) = chaining_values(left, right)?;
+    Ok(hazmat::merge_subtrees_non_root(left, right, Mode::Hash))
+}
+
+fn root_hash(left: &[u8], right: &[u8]) -> Result<Hash, MerkleError> {
+    let (left, right) = chaining_values(left, right)?;
+    Ok(hazmat::merge_subtrees_root(left, right, Mode::Hash)
+        .as_bytes()
+        .as_slice()
+        .into())
+}
+
+fn chaining_values<'a>(
+    left: &'a [u8],
+    right: &'a [u8],
+) -> Result<(&'a ChainingValue, &'a ChainingValue), MerkleError> {
+    let check = |cv: &'a [u8]| {
+        cv.try_into()
+            .map_err(|_| MerkleError::ChainingValueLength { received: cv.len() })
+    };
+    Ok((check(left)?, check(right)?))
+}
+
+/// Merkle tree over the chunks of a file whose root is the BLAKE3 hash of the
+/// file. Parts of the file can be sent as slices that the receiver checks
+/// against the root alone, as in Bao.
+#[derive(Debug)]
+pub struct Blake3Tree {
+    tree: MerkleTree<Blake3>,
+    count_bytes: usize,
+    root: Hash,
+}
+
+impl Blake3Tree {
+    pub fn build(data: &[u8]) -> Blake3Tree {
+        let chunks: Vec<&[u8]> = data.chunks(CHUNK_LEN).collect();
+        let tree = MerkleTree::build_with_mode(&chunks, Blake3::new(), TreeMode::BLAKE3);
+
+        // The root of BLAKE3's tree is finalized differently from the nodes
+        // below it, and for a single chunk it can't be derived from the
+        // chunk's chaining value.
+        let root = if chunks.len() <= 1 {
+            ::blake3::hash(data).as_bytes().as_slice().into()
+        } else {
+            let height = level_widths(chunks.len()).len() - 1;
+            root_hash(&tree.node(height - 1, 0), &tree.node(height - 1, 1))
+                .unwrap_or_else(|e| panic!("{}", e))
+        };
+
+        Blake3Tree {
+            tree,
+            count_bytes: data.len(),
+            root,
+        }
+    }
+
+    /// BLAKE3 hash of the file.
+    pub fn root_hash(&self) -> &Hash {
+        &self.root
+    }
+
+    /// Tree of chaining values built in `TreeMode::BLAKE3`. Its own root hash
+    /// is the chaining value of the top node, not the BLAKE3 hash.
+    pub fn tree(&self) -> &MerkleTree<Blake3> {
+        &self.tree
+    }
+
+    pub fn count_bytes(&self) -> usize {
+        self.count_bytes
+    }
+
+    /// Encodes the bytes `start..start + len` of `data`, the file the tree
+    /// was built over, together with the chaining values needed to check
+    /// them. The encoding is Bao's combined slice format: the length of the
+    /// file as 8 bytes little-endian, then in pre-order every parent on the
+    /// way to the requested chunks as its two child chaining values and
+    /// every requested chunk in full. An empty or out of range request
+    /// encodes the last chunk, which proves the length of the file.
+    pub fn slice(&self, data: &[u8], start: usize, len: usize) -> Result<Vec<u8>, MerkleError> {
+        if data.len() != self.count_bytes {
+            return Err(MerkleError::Malformed("data is not the file of the tree"));
+        }
+
+        let count_chunks = self.tree.leaves().len().max(1);
+        let range = chunk_range(count_chunks, start, len);
+        let mut slice = (self.count_bytes as u64).to_le_bytes().to_vec();
+        self.encode_subtree(data, 0, count_chunks, range, &mut slice);
+
+        Ok(slice)
+    }
+
+    fn encode_subtree(
+        &self,
+        data: &[u8],
+        first: usize,
+        end: usize,
+        range: (usize, usize),
+        slice: &mut Vec<u8>,
+    ) {
+        if end - first == 1 {
+            let chunk_end = (end * CHUNK_LEN).min(self.count_bytes);
+            slice.extend_from_slice(&data[first * CHUNK_LEN..chunk_end]);
+            return;
+        }
+
+        let split = first + left_subtree_len(end - first);
+        slice.extend_from_slice(self.subtree(first, split));
+        slice.extend_from_slice(self.subtree(split, end));
+        if range.0 < split {
+            self.encode_subtree(data, first, split, range, slice);
+        }
+        if range.1 > split {
+            self.encode_subtree(data, split, end, range, slice);
+        }
+    }
+
+    /// Chaining value of the subtree over the chunks `first..end`.
+    fn subtree(&self, first: usize, end: usize) -> &[u8] {
+        let level = (end - first).next_power_of_two().trailing_zeros() as usize;
+        &self.tree.store().level(level)[first >> level]
+    }
+}
+
+/// Checks a slice encoded by `Blake3Tree::slice` against the BLAKE3 hash of
+/// the whole file and returns the bytes `start..start + len` of the file, cut
+/// short at its end. As in Bao, the length of the file is only checked as far
+/// as it decides the shape of the tree, unless the slice holds the last chunk.
+pub fn verify_slice(
+    slice: &[u8],
+    root: &[u8],
+    start: usize,
+    len: usize,
+) -> Result<Vec<u8>, MerkleError> {
+    if slice.len() < 8 {
+        return Err(MerkleError::Malformed("unexpected end of input"));
+    }
+    let (header, input) = slice.split_at(8);
+    let mut count_bytes = [0u8; 8];
+    count_bytes.copy_from_slice(header);
+    let count_bytes = usize::try_from(u64::from_le_bytes(count_bytes))
+        .map_err(|_| MerkleError::Malformed("file too large"))?;
+
+    let count_chunks = count_bytes.div_ceil(CHUNK_LEN).max(1);
+    let range = chunk_range(count_chunks, start, len);
+    let mut decoder = SliceDecoder {
+        count_bytes,
+        count_chunks,
+        range,
+        input,
+        output: Vec::new(),
+    };
+    decoder.decode_subtree(0, count_chunks, root)?;
+    if !decoder.input.is_empty() {
+        return Err(MerkleError::Malformed("trailing bytes"));
+    }
+
+    let offset = range.0 * CHUNK_LEN;
+    let first = start.clamp(offset, offset + decoder.output.len());
+    let end = start
+        .saturating_add(len)
+        .clamp(first, offset + decoder.output.len());
+    Ok(decoder.output[first - offset..end - offset].to_vec())
+}
+
+struct SliceDecoder<'a> {
+    count_bytes: usize,
+    count_chunks: usize,
+    range: (usize, usize),
+    input: &'a [u8],
+    output: Vec<u8>,
+}
+
+impl<'a> SliceDecoder<'a> {
+    fn decode_subtree(
+        &mut self,
+        first: usize,
+        end: usize,
+        expected: &[u8],
+    ) -> Result<(), MerkleError> {
+        let is_root = end - first == self.count_chunks;
+
+        if end - first == 1 {
+            let chunk_len = end.saturating_mul(CHUNK_LEN).min(self.count_bytes) - first * CHUNK_LEN;
+            let chunk = self.read(chunk_len)?;
+            let hash = if is_root {
+                ::blake3::hash(chunk).as_bytes().to_vec()
+            } else {
+                chunk_chaining_value(first, chunk)?.to_vec()
+            };
+            if !ct_eq(&hash, expected) {
+                return Err(MerkleError::Malformed("chunk does not match its hash"));
+            }
+            self.output.extend_from_slice(chunk);
+            return Ok(());
+        }
+
+        let children = self.read(64)?;
+        let (left, right) = children.split_at(32);
+        let hash = if is_root {
+            root_hash(left, right)?
+        } else {
+            parent_chaining_value(left, right)?.as_slice().into()
+        };
+        if !ct_eq(&hash, expected) {
+            return Err(MerkleError::Malformed("parent does not match its hash"));
+        }
+
+        let split = first + left_subtree_len(end - first);
+        if self.range.0 < split {
+            self.decode_subtree(first, split, left)?;
+        }
+        if self.range.1 > split {
+            self.decode_subtree(split, end, right)?;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, len: usize) -> Result<&'a [u8], MerkleError> {
+        if self.input.len() < len {
+            return Err(MerkleError::Malformed("unexpected end of input"));
+        }
+        let (head, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(head)
+    }
+}
+
+/// Chunks holding the bytes `start..start + len` of a file; the last chunk
+/// when there are none.
+fn chunk_range(count_chunks: usize, start: usize, len: usize) -> (usize, usize) {
+    let first = (start / CHUNK_LEN).min(count_chunks - 1);
+    let end = start
+        .saturating_add(len)
+        .div_ceil(CHUNK_LEN)
+        .clamp(first + 1, count_chunks);
+    (first, end)
+}
+
+/// Number of chunks in the left subtree of a BLAKE3 tree over `count_chunks`
+/// chunks: the largest power of two smaller than `count_chunks`.
+fn left_subtree_len(count_chunks: usize) -> usize {
+    1 << (usize::BITS - 1 - (count_chunks - 1).leading_zeros())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_slice, Blake3, Blake3Tree, CHUNK_LEN};
+    use crate::{Digest, Hash, MerkleError, MerkleTree, TreeMode};
+
+    /// Input of the official BLAKE3 test vectors.
+    fn input(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    const LENGTHS: [usize; 12] = [
+        0, 1, 1023, 1024, 1025, 2048, 2049, 3072, 3073, 4096, 8193, 31744,
+    ];
+
+    #[test]
+    fn test_blake3_known_answers() {
+        let t: MerkleTree<Blake3> = MerkleTree::build_with_hasher(&[] as &[&str], Blake3::new());
+        assert_eq!(
+            Hash::from(t.root_hash()).to_string(),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+
+        let t: MerkleTree<Blake3> =
+            MerkleTree::build_with_mode(&["abc"], Blake3::new(), TreeMode::BITCOIN);
+        assert_eq!(
+            Hash::from(t.root_hash()).to_string(),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+
+    #[test]
+    fn test_blake3_tree_yields_the_blake3_hash_of_the_file() {
+        let vectors = [
+            (
+                0,
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+            (
+                1,
+                "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213",
+            ),
+            (
+                1024,
+                "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+            ),
+            (
+                1025,
+                "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444",
+            ),
+        ];
+        for &(len, root) in vectors.iter() {
+            assert_eq!(Blake3Tree::build(&input(len)).root_hash().to_string(), root);
+        }
+
+        for &len in LENGTHS.iter() {
+            let data = input(len);
+            assert_eq!(
+                Blake3Tree::build(&data).root_hash().as_bytes(),
+                blake3::hash(&data).as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_blake3_mode_trees_can_be_pushed_to_and_proved() {
+        let data = input(5 * CHUNK_LEN + 17);
+        let chunks: Vec<&[u8]> = data.chunks(CHUNK_LEN).collect();
+        let built = Blake3Tree::build(&data);
+
+        let mut t = MerkleTree::build_with_mode(&chunks[..1], Blake3::new(), TreeMode::BLAKE3);
+        t.extend(&chunks[1..]);
+        assert_eq!(t.root_hash(), built.tree().root_hash());
+
+        for (position, chunk) in chunks.iter().enumerate() {
+            assert!(t.prove(position).verify(
+                t.root_hash(),
+                chunks.len(),
+                position,
+                chunk,
+                t.mode(),
+                &mut Blake3::new()
+            ));
+        }
+    }
+
+    #[test]
+    fn test_blake3_mode_rejects_values_that_are_not_one_chunk() {
+        let long = vec![b'a'; 2 * CHUNK_LEN];
+        let values: [&[u8]; 2] = [&long, b"x"];
+        let err = MerkleTree::try_build_with_mode(&values, Blake3::new(), TreeMode::BLAKE3);
+        assert_eq!(
+            err.unwrap_err(),
+            MerkleError::ChunkLengthOutOfRange {
+                position: 0,
+                received: 2 * CHUNK_LEN
+            }
+        );
+        let empty: [&[u8]; 2] = [b"", b"x"];
+        assert!(MerkleTree::try_build_with_mode(&empty, Blake3::new(), TreeMode::BLAKE3).is_err());
+
+        let values: [&[u8]; 2] = [b"a", b"x"];
+        let mut t = MerkleTree::build_with_mode(&values, Blake3::new(), TreeMode::BLAKE3);
+        let long = vec![b'b'; 5 * CHUNK_LEN];
+        assert!(t.try_verify(0, &long.as_slice()).is_err());
+        assert!(t.try_update(0, &long.as_slice()).is_err());
+        assert!(t.try_push(&&b""[..]).is_err());
+        assert_eq!(t.count_leaves(), 2);
+
+        let proof = t.prove(0);
+        let mode = TreeMode::BLAKE3;
+        assert!(!proof.verify(
+            t.root_hash(),
+            2,
+            0,
+            &long.as_slice(),
+            mode,
+            &mut Blake3::new()
+        ));
+        assert!(proof
+            .try_verify(
+                t.root_hash(),
+                2,
+                0,
+                &long.as_slice(),
+                mode,
+                &mut Blake3::new()
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_blake3_mode_rejects_hashers_of_another_output_size() {
+        let err =
+            MerkleTree::try_build_with_mode(&["a", "b"], sha2::Sha512::new(), TreeMode::BLAKE3);
+        assert_eq!(
+            err.unwrap_err(),
+            MerkleError::ChainingValueLength { received: 64 }
+        );
+    }
+
+    #[test]
+    fn test_slices_verify_against_the_blake3_hash() {
+        for &len in LENGTHS.iter() {
+            let data = input(len);
+            let tree = Blake3Tree::build(&data);
+            for &(start, slice_len) in [
+                (0, 0),
+                (0, 1),
+                (0, len),
+                (1000, 100),
+                (2047, 2),
+                (5000, 3000),
+                (len, 10),
+                (len + 5000, 1),
+            ]
+            .iter()
+            {
+                let slice = tree.slice(&data, start, slice_len).unwrap();
+                let verified = verify_slice(&slice, tree.root_hash(), start, slice_len).unwrap();
+
+                let first = start.min(len);
+                let end = (start + slice_len).min(len);
+                assert_eq!(verified, &data[first..end]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tampered_slices_are_rejected() {
+        let data = input(4 * CHUNK_LEN + 100);
+        let tree = Blake3Tree::build(&data);
+        let slice = tree.slice(&data, 2500, 1000).unwrap();
+
+        for i in 8..slice.len() {
+            let mut tampered = slice.clone();
+            tampered[i] ^= 1;
+            assert!(verify_slice(&tampered, tree.root_hash(), 2500, 1000).is_err());
+        }
+        let mut shorter = slice.clone();
+        shorter[..8].copy_from_slice(&(3 * CHUNK_LEN as u64).to_le_bytes());
+        assert!(verify_slice(&shorter, tree.root_hash(), 2500, 1000).is_err());
+        assert!(verify_slice(&slice[..slice.len() - 1], tree.root_hash(), 2500, 1000).is_err());
+        assert!(verify_slice(&slice, tree.root_hash(), 0, 1000).is_err());
+    }
+}
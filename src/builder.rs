@@ -0,0 +1,248 @@
+// Streaming Merkle Tree construction
+use digest::{Digest, FixedOutputReset, Output};
+use std::io::{self, Read};
+
+use super::hasher::hash_empty;
+use super::tree::{_build_from_leaves_with_hasher, build_upper_level, level_widths};
+use super::{AsBytes, DefaultHasher, MerkleError, MerkleTree, TreeMode};
+
+/// Computes the root of a `MerkleTree` from values that arrive one at a time,
+/// without holding them or their hashes. Only the roots of the complete
+/// subtrees seen so far are kept, at most one per level.
+#[derive(Debug)]
+pub struct MerkleTreeBuilder<H = DefaultHasher>
+where
+    H: Digest + FixedOutputReset,
+{
+    hasher: H,
+    mode: TreeMode,
+    count_leaves: usize,
+    /// Root of the complete subtree of `2^level` leaves at index `level`,
+    /// present when bit `level` of `count_leaves` is set.
+    frontier: Vec<Option<Output<H>>>,
+    leaves: Option<Vec<Output<H>>>,
+}
+
+impl<H> MerkleTreeBuilder<H>
+where
+    H: Digest + FixedOutputReset,
+{
+    pub fn new() -> MerkleTreeBuilder<H>
+    where
+        H: Default,
+    {
+        MerkleTreeBuilder::with_hasher(Default::default())
+    }
+
+    pub fn with_hasher(hasher: H) -> MerkleTreeBuilder<H> {
+        MerkleTreeBuilder::with_mode(hasher, TreeMode::DEFAULT)
+    }
+
+    pub fn with_mode(hasher: H, mode: TreeMode) -> MerkleTreeBuilder<H> {
+        MerkleTreeBuilder {
+            hasher,
+            mode,
+            count_leaves: 0,
+            frontier: Vec::new(),
+            leaves: None,
+        }
+    }
+
+    /// Keeps every leaf hash so that `into_tree` can build the full tree.
+    /// This takes memory linear in the number of leaves.
+    pub fn keep_leaves(mut self) -> MerkleTreeBuilder<H> {
+        if self.leaves.is_none() {
+            self.leaves = Some(Vec::new());
+        }
+        self
+    }
+
+    pub fn count_leaves(&self) -> usize {
+        self.count_leaves
+    }
+
+    /// Appends a leaf, merging every complete subtree it finishes.
+    pub fn push<T>(&mut self, value: &T)
+    where
+        T: AsBytes,
+    {
+        self.try_push(value).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_push<T>(&mut self, value: &T) -> Result<(), MerkleError>
+    where
+        T: AsBytes,
+    {
+        let leaf = self
+            .mode
+            .hash_leaf(self.count_leaves, value, &mut self.hasher)?;
+        if let Some(ref mut leaves) = self.leaves {
+            leaves.push(leaf.clone());
+        }
+
+        let mut node = leaf;
+        let mut level = 0;
+        while let Some(left) = self.frontier.get_mut(level).and_then(Option::take) {
+            node = self.mode.hash_node(&left, &node, &mut self.hasher)?;
+            level += 1;
+        }
+        if level == self.frontier.len() {
+            self.frontier.push(None);
+        }
+        self.frontier[level] = Some(node);
+        self.count_leaves += 1;
+        Ok(())
+    }
+
+    /// Appends every value of `values`, see `push`.
+    pub fn extend<I>(&mut self, values: I)
+    where
+        I: IntoIterator,
+        I::Item: AsBytes,
+    {
+        for value in values {
+            self.push(&value);
+        }
+    }
+
+    /// Appends the contents of `reader` as leaves of `chunk_len` bytes; only
+    /// the last one may be shorter. Returns the number of leaves appended.
+    /// Chunks the mode cannot hash as leaves are reported as invalid input.
+    pub fn read_chunks<R>(&mut self, mut reader: R, chunk_len: usize) -> io::Result<usize>
+    where
+        R: Read,
+    {
+        assert!(chunk_len > 0, "chunks must not be empty");
+
+        let mut chunk = vec![0u8; chunk_len];
+        let mut count = 0;
+        loop {
+            let mut len = 0;
+            while len < chunk_len {
+                match reader.read(&mut chunk[len..]) {
+                    Ok(0) => break,
+                    Ok(n) => len += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            if len == 0 {
+                return Ok(count);
+            }
+
+            self.try_push(&&chunk[..len])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            count += 1;
+            if len < chunk_len {
+                return Ok(count);
+            }
+        }
+    }
+
+    /// Root hash of the tree over all leaves appended so far, the same as
+    /// that of a `MerkleTree` built from them in the same mode.
+    ///
+    /// Going up from the leaves, the only incomplete node of each level is
+    /// the one to the right of the last complete subtree, so it is either
+    /// paired with that subtree or lone. The leaves were checked as they were
+    /// pushed, so the nodes above them always hash.
+    pub fn root_hash(&mut self) -> Output<H> {
+        if self.count_leaves == 0 {
+            return hash_empty(&mut self.hasher);
+        }
+
+        let height = level_widths(self.count_leaves).len() - 1;
+        let mut partial: Option<Output<H>> = None;
+        for level in 0..height {
+            let node = match (&self.frontier[level], partial) {
+                (Some(left), Some(right)) => {
+                    Some(self.mode.hash_node(left, &right, &mut self.hasher))
+                }
+                (Some(node), None) => Some(self.mode.hash_lone_node(node, &mut self.hasher)),
+                (None, Some(node)) => Some(self.mode.hash_lone_node(&node, &mut self.hasher)),
+                (None, None) => None,
+            };
+            partial = node.transpose().unwrap_or_else(|e| panic!("{}", e));
+        }
+
+        match partial {
+            Some(root) => root,
+            None => self.frontier[height].clone().unwrap(),
+        }
+    }
+
+    /// Builds the full tree, if the leaves were kept with `keep_leaves`.
+    pub fn into_tree(self) -> Option<MerkleTree<H>> {
+        let leaves = self.leaves?;
+        let tree =
+            _build_from_leaves_with_hasher(&leaves, self.hasher, self.mode, build_upper_level);
+        Some(tree.unwrap_or_else(|e| panic!("{}", e)))
+    }
+}
+
+impl<H> Default for MerkleTreeBuilder<H>
+where
+    H: Digest + FixedOutputReset + Default,
+{
+    fn default() -> MerkleTreeBuilder<H> {
+        MerkleTreeBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{DefaultHasher, MerkleTree, TreeMode};
+    use super::MerkleTreeBuilder;
+
+    const MODES: [TreeMode; 4] = [
+        TreeMode::DEFAULT,
+        TreeMode::RFC6962,
+        TreeMode::BITCOIN,
+        TreeMode::BLAKE3,
+    ];
+
+    #[test]
+    fn test_streamed_root_matches_the_built_tree() {
+        let values: Vec<String> = (0..70).map(|i| i.to_string()).collect();
+        for &mode in MODES.iter() {
+            let mut builder = MerkleTreeBuilder::with_mode(DefaultHasher::new(), mode);
+            for count in 0..values.len() + 1 {
+                let t: MerkleTree =
+                    MerkleTree::build_with_mode(&values[..count], DefaultHasher::new(), mode);
+                assert_eq!(builder.root_hash().as_slice(), t.root_hash());
+
+                if count < values.len() {
+                    builder.push(&values[count]);
+                }
+            }
+            assert!(builder.frontier.len() <= 7);
+        }
+    }
+
+    #[test]
+    fn test_reading_chunks_matches_the_built_tree() {
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        for &chunk_len in [1, 100, 1024, 9_999, 10_000, 20_000].iter() {
+            let mut builder = MerkleTreeBuilder::<DefaultHasher>::new();
+            let count = builder.read_chunks(data.as_slice(), chunk_len).unwrap();
+
+            let chunks: Vec<&[u8]> = data.chunks(chunk_len).collect();
+            let t: MerkleTree = MerkleTree::build(&chunks);
+            assert_eq!(count, chunks.len());
+            assert_eq!(builder.root_hash().as_slice(), t.root_hash());
+        }
+    }
+
+    #[test]
+    fn test_kept_leaves_materialize_the_full_tree() {
+        let values = ["a", "b", "c", "d", "e"];
+        let mut builder = MerkleTreeBuilder::<DefaultHasher>::new();
+        builder.extend(values.iter().cloned());
+        assert!(builder.into_tree().is_none());
+
+        let mut builder = MerkleTreeBuilder::<DefaultHasher>::new().keep_leaves();
+        builder.extend(values.iter().cloned());
+        let t: MerkleTree = MerkleTree::build(&values);
+        assert_eq!(builder.into_tree().unwrap().store(), t.store());
+    }
+}
@@ -0,0 +1,605 @@
+// Command-line interface of the merkletree binary
+use serde_json::{json, Value};
+use sha2::{Sha224, Sha384, Sha512, Sha512_256};
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Write};
+
+use merkletree::bitcoin::DoubleSha256;
+use merkletree::blake3::Blake3;
+use merkletree::{
+    DefaultHasher, Hash, HashAlgorithm, InclusionProof, MerkleTree, MerkleTreeBuilder, TreeMode,
+};
+
+const USAGE: &str = "\
+Usage: merkletree <command> [options] [file...]
+
+Commands:
+  root      Print the root hash of a tree over the leaves
+  prove     Print an inclusion proof for the leaf at --index
+  verify    Check a proof printed by `prove` against a root
+  diff      Compare two trees saved with `root --save`
+
+Leaves are the lines of stdin if no file is given and the contents of each
+file otherwise. With --chunk-size they are fixed-size chunks instead.
+
+Options:
+  -a, --algorithm <name>  sha256 (default), sha224, sha384, sha512,
+                          sha512-256, double-sha256 or blake3
+  -m, --mode <name>       default, rfc6962, bitcoin or blake3
+  -f, --format <name>     hex (default) or json
+  -c, --chunk-size <n>    split the input into leaves of <n> bytes
+  -i, --index <n>         position of the proven leaf
+  -n, --leaves <n>        number of leaves of the tree the root belongs to
+                          (verify)
+      --save <file>       write the tree in the binary format (root)
+      --proof <file>      proof to verify, - for stdin (verify)
+      --root <hex>        root to verify against (verify)
+      --leaf <text>       value of the proven leaf (verify)
+      --leaf-file <file>  file holding the value of the proven leaf (verify)
+
+Exit status is 0 on success, 1 if a proof is invalid or trees differ and 2
+on errors.
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Root,
+    Prove,
+    Verify,
+    Diff,
+    Help,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Hex,
+    Json,
+}
+
+#[derive(Debug)]
+struct Options {
+    command: Command,
+    algorithm: Option<String>,
+    mode: String,
+    format: Format,
+    chunk_size: Option<usize>,
+    index: Option<usize>,
+    leaves: Option<usize>,
+    save: Option<String>,
+    proof: Option<String>,
+    root: Option<String>,
+    leaf: Option<Vec<u8>>,
+    leaf_file: Option<String>,
+    paths: Vec<String>,
+}
+
+/// Runs the command line `args`, without the program name, and returns the
+/// exit status.
+pub fn main(args: &[String]) -> i32 {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    match run(args, &mut stdin.lock(), &mut stdout.lock()) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(message) => {
+            eprintln!("merkletree: {}", message);
+            2
+        }
+    }
+}
+
+/// Runs the command line `args` and tells whether a proof was valid or two
+/// trees were equal. Other commands always succeed unless they fail.
+fn run(args: &[String], stdin: &mut dyn BufRead, stdout: &mut dyn Write) -> Result<bool, String> {
+    let options = parse_args(args)?;
+    let mut proof = None;
+    if options.command == Command::Verify {
+        proof = Some(read_proof(&options, stdin)?);
+    }
+
+    let algorithm = options
+        .algorithm
+        .clone()
+        .or_else(|| proof.as_ref().and_then(|p| p.algorithm.clone()))
+        .unwrap_or_else(|| "sha256".to_string());
+    let mut cli = Cli {
+        options: &options,
+        algorithm: &algorithm,
+        stdin,
+        stdout,
+    };
+    match algorithm.as_str() {
+        "sha256" => cli.execute::<DefaultHasher>(proof),
+        "sha224" => cli.execute::<Sha224>(proof),
+        "sha384" => cli.execute::<Sha384>(proof),
+        "sha512" => cli.execute::<Sha512>(proof),
+        "sha512-256" => cli.execute::<Sha512_256>(proof),
+        "double-sha256" => cli.execute::<DoubleSha256>(proof),
+        "blake3" => cli.execute::<Blake3>(proof),
+        other => Err(format!("unknown hash algorithm `{}`", other)),
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let command = match args.first().map(String::as_str) {
+        Some("root") => Command::Root,
+        Some("prove") => Command::Prove,
+        Some("verify") => Command::Verify,
+        Some("diff") => Command::Diff,
+        Some("help") | Some("-h") | Some("--help") | None => Command::Help,
+        Some(other) => {
+            return Err(format!(
+                "unknown command `{}`\n\n{}",
+                other,
+                USAGE.trim_end()
+            ))
+        }
+    };
+
+    let mut options = Options {
+        command,
+        algorithm: None,
+        mode: "default".to_string(),
+        format: Format::Hex,
+        chunk_size: None,
+        index: None,
+        leaves: None,
+        save: None,
+        proof: None,
+        root: None,
+        leaf: None,
+        leaf_file: None,
+        paths: Vec::new(),
+    };
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("missing value for `{}`", arg))
+        };
+        match arg.as_str() {
+            "-a" | "--algorithm" => options.algorithm = Some(value()?),
+            "-m" | "--mode" => options.mode = value()?,
+            "-f" | "--format" => {
+                options.format = match value()?.as_str() {
+                    "hex" => Format::Hex,
+                    "json" => Format::Json,
+                    other => return Err(format!("unknown output format `{}`", other)),
+                }
+            }
+            "-c" | "--chunk-size" => {
+                options.chunk_size = match parse_number(arg, &value()?)? {
+                    0 => return Err("chunk size must not be 0".to_string()),
+                    n => Some(n),
+                }
+            }
+            "-i" | "--index" => options.index = Some(parse_number(arg, &value()?)?),
+            "-n" | "--leaves" => options.leaves = Some(parse_number(arg, &value()?)?),
+            "--save" => options.save = Some(value()?),
+            "--proof" => options.proof = Some(value()?),
+            "--root" => options.root = Some(value()?),
+            "--leaf" => options.leaf = Some(value()?.into_bytes()),
+            "--leaf-file" => options.leaf_file = Some(value()?),
+            "-h" | "--help" => options.command = Command::Help,
+            flag if flag.starts_with('-') && flag != "-" => {
+                return Err(format!("unknown option `{}`", flag))
+            }
+            path => options.paths.push(path.to_string()),
+        }
+    }
+
+    match options.command {
+        Command::Prove if options.index.is_none() => Err("prove needs --index".to_string()),
+        Command::Verify if options.proof.is_none() => Err("verify needs --proof".to_string()),
+        Command::Verify if options.root.is_none() => Err("verify needs --root".to_string()),
+        Command::Verify if options.leaf.is_none() == options.leaf_file.is_none() => {
+            Err("verify needs either --leaf or --leaf-file".to_string())
+        }
+        Command::Verify if options.leaves.is_none() => Err("verify needs --leaves".to_string()),
+        Command::Diff if options.paths.len() != 2 => {
+            Err("diff needs the files of two saved trees".to_string())
+        }
+        _ => Ok(options),
+    }
+}
+
+fn parse_number(flag: &str, value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("`{}` needs a number, got `{}`", flag, value))
+}
+
+fn parse_mode(name: &str) -> Result<TreeMode, String> {
+    match name {
+        "default" => Ok(TreeMode::DEFAULT),
+        "rfc6962" => Ok(TreeMode::RFC6962),
+        "bitcoin" => Ok(TreeMode::BITCOIN),
+        "blake3" => Ok(TreeMode::BLAKE3),
+        other => Err(format!("unknown tree mode `{}`", other)),
+    }
+}
+
+/// Proof to verify along with what its JSON form records about it. The root
+/// it records is left out: a proof cannot vouch for the root it is checked
+/// against.
+struct ProofDocument {
+    proof: InclusionProof,
+    algorithm: Option<String>,
+    index: Option<usize>,
+}
+
+/// Reads the output of `prove`, either the hex of the binary encoding of the
+/// proof or the JSON object.
+fn read_proof(options: &Options, stdin: &mut dyn BufRead) -> Result<ProofDocument, String> {
+    let path = options.proof.as_deref().unwrap_or("-");
+    let mut text = String::new();
+    if path == "-" {
+        stdin.read_to_string(&mut text)
+    } else {
+        File::open(path).and_then(|mut file| file.read_to_string(&mut text))
+    }
+    .map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let text = text.trim();
+
+    if !text.starts_with('{') {
+        let bytes: Hash = text.parse().map_err(|e| format!("proof: {}", e))?;
+        let proof = InclusionProof::from_bytes(&bytes).map_err(|e| format!("proof: {}", e))?;
+        return Ok(ProofDocument {
+            proof,
+            algorithm: None,
+            index: None,
+        });
+    }
+
+    let mut document: Value = serde_json::from_str(text).map_err(|e| format!("proof: {}", e))?;
+    let proof =
+        serde_json::from_value(document["proof"].take()).map_err(|e| format!("proof: {}", e))?;
+    let field = |name: &str| document[name].as_str().map(str::to_string);
+    Ok(ProofDocument {
+        proof,
+        algorithm: field("algorithm"),
+        index: document["index"].as_u64().map(|i| i as usize),
+    })
+}
+
+struct Cli<'a> {
+    options: &'a Options,
+    algorithm: &'a str,
+    stdin: &'a mut dyn BufRead,
+    stdout: &'a mut dyn Write,
+}
+
+impl Cli<'_> {
+    fn execute<H>(&mut self, proof: Option<ProofDocument>) -> Result<bool, String>
+    where
+        H: HashAlgorithm + Default,
+    {
+        match self.options.command {
+            Command::Root => self.root::<H>(),
+            Command::Prove => self.prove::<H>(),
+            Command::Verify => self.verify::<H>(proof.expect("proof is read beforehand")),
+            Command::Diff => self.diff::<H>(),
+            Command::Help => {
+                self.print(USAGE.trim_end())?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn root<H>(&mut self) -> Result<bool, String>
+    where
+        H: HashAlgorithm + Default,
+    {
+        let mut builder = self.build::<H>(self.options.save.is_some())?;
+        let root = Hash::from(builder.root_hash().as_slice());
+        let count_leaves = builder.count_leaves();
+        if let Some(ref path) = self.options.save {
+            let tree = builder.into_tree().expect("leaves are kept when saving");
+            let bytes = tree.to_bytes().map_err(|e| e.to_string())?;
+            fs::write(path, bytes).map_err(|e| format!("cannot write {}: {}", path, e))?;
+        }
+
+        match self.options.format {
+            Format::Hex => self.print(&root.to_string())?,
+            Format::Json => self.print_json(json!({
+                "algorithm": self.algorithm,
+                "mode": self.options.mode,
+                "leaves": count_leaves,
+                "root": root,
+            }))?,
+        }
+        Ok(true)
+    }
+
+    fn prove<H>(&mut self) -> Result<bool, String>
+    where
+        H: HashAlgorithm + Default,
+    {
+        let index = self.options.index.expect("checked by parse_args");
+        let tree = self
+            .build::<H>(true)?
+            .into_tree()
+            .expect("leaves are kept for proofs");
+        let proof = tree.try_prove(index).map_err(|e| e.to_string())?;
+
+        match self.options.format {
+            Format::Hex => {
+                let bytes = proof.to_bytes().map_err(|e| e.to_string())?;
+                self.print(&Hash::from(bytes).to_string())?
+            }
+            Format::Json => self.print_json(json!({
+                "algorithm": self.algorithm,
+                "mode": self.options.mode,
+                "root": Hash::from(tree.root_hash()).to_string(),
+                "index": index,
+                "proof": proof,
+            }))?,
+        }
+        Ok(true)
+    }
+
+    fn verify<H>(&mut self, document: ProofDocument) -> Result<bool, String>
+    where
+        H: HashAlgorithm + Default,
+    {
+        let root: Hash = self
+            .options
+            .root
+            .as_deref()
+            .expect("checked by parse_args")
+            .parse()
+            .map_err(|e| format!("root: {}", e))?;
+        let index = self
+            .options
+            .index
+            .or(document.index)
+            .ok_or("verify needs --index unless the proof is JSON")?;
+        let leaf = match (&self.options.leaf, &self.options.leaf_file) {
+            (Some(leaf), _) => leaf.clone(),
+            (None, Some(path)) => {
+                fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?
+            }
+            (None, None) => unreachable!("checked by parse_args"),
+        };
+
+        let count_leaves = self.options.leaves.expect("checked by parse_args");
+        let mode = parse_mode(&self.options.mode)?;
+        if document.proof.mode() != mode {
+            return Err(format!(
+                "proof is not for a tree in mode `{}`",
+                self.options.mode
+            ));
+        }
+        let valid = document.proof.verify(
+            &root,
+            count_leaves,
+            index,
+            &leaf.as_slice(),
+            mode,
+            &mut H::default(),
+        );
+        match self.options.format {
+            Format::Hex => self.print(if valid { "valid" } else { "invalid" })?,
+            Format::Json => self.print_json(json!({ "valid": valid }))?,
+        }
+        Ok(valid)
+    }
+
+    fn diff<H>(&mut self) -> Result<bool, String>
+    where
+        H: HashAlgorithm + Default,
+    {
+        let load = |path: &String| -> Result<MerkleTree<H>, String> {
+            let bytes = fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+            MerkleTree::from_bytes(&bytes).map_err(|e| format!("{}: {}", path, e))
+        };
+        let left = load(&self.options.paths[0])?;
+        let right = load(&self.options.paths[1])?;
+        let diff = left
+            .try_diff(&right)
+            .map_err(|e| format!("cannot compare the trees: {}", e))?;
+        let ranges: Vec<(usize, usize)> = diff.ranges().iter().map(|r| (r.start, r.end)).collect();
+
+        match self.options.format {
+            Format::Hex => {
+                for &(start, end) in &ranges {
+                    self.print(&format!("{}..{}", start, end))?;
+                }
+            }
+            Format::Json => self.print_json(json!({
+                "equal": diff.is_empty(),
+                "left": { "root": Hash::from(left.root_hash()).to_string(), "leaves": left.leaves().len() },
+                "right": { "root": Hash::from(right.root_hash()).to_string(), "leaves": right.leaves().len() },
+                "ranges": ranges,
+                "comparisons": diff.comparisons(),
+            }))?,
+        }
+        Ok(diff.is_empty())
+    }
+
+    /// Hashes the leaves given on the command line, keeping them if a full
+    /// tree is needed afterwards.
+    fn build<H>(&mut self, keep_leaves: bool) -> Result<MerkleTreeBuilder<H>, String>
+    where
+        H: HashAlgorithm + Default,
+    {
+        let mode = parse_mode(&self.options.mode)?;
+        let mut builder = MerkleTreeBuilder::with_mode(H::default(), mode);
+        if keep_leaves {
+            builder = builder.keep_leaves();
+        }
+
+        let read_error = |path: &str, e: io::Error| format!("cannot read {}: {}", path, e);
+        match (self.options.paths.is_empty(), self.options.chunk_size) {
+            (true, Some(chunk_size)) => {
+                builder
+                    .read_chunks(&mut *self.stdin, chunk_size)
+                    .map_err(|e| read_error("stdin", e))?;
+            }
+            (true, None) => {
+                for line in BufRead::split(&mut *self.stdin, b'\n') {
+                    builder.push(&line.map_err(|e| read_error("stdin", e))?.as_slice());
+                }
+            }
+            (false, Some(chunk_size)) => {
+                for path in &self.options.paths {
+                    File::open(path)
+                        .and_then(|file| builder.read_chunks(file, chunk_size))
+                        .map_err(|e| read_error(path, e))?;
+                }
+            }
+            (false, None) => {
+                for path in &self.options.paths {
+                    builder.push(&fs::read(path).map_err(|e| read_error(path, e))?.as_slice());
+                }
+            }
+        }
+        Ok(builder)
+    }
+
+    fn print(&mut self, line: &str) -> Result<(), String> {
+        writeln!(self.stdout, "{}", line).map_err(|e| format!("cannot write output: {}", e))
+    }
+
+    fn print_json(&mut self, value: Value) -> Result<(), String> {
+        self.print(&value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run;
+    use merkletree::{DefaultHasher, MerkleTree, TreeMode};
+    use std::env;
+    use std::fs;
+
+    fn run_with(args: &[&str], stdin: &str) -> (Result<bool, String>, String) {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let mut stdout = Vec::new();
+        let result = run(&args, &mut stdin.as_bytes(), &mut stdout);
+        (result, String::from_utf8(stdout).unwrap())
+    }
+
+    fn temp_path(name: &str) -> String {
+        let mut path = env::temp_dir();
+        path.push(format!("merkletree-cli-{}-{}", std::process::id(), name));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_root_of_stdin_lines() {
+        let t: MerkleTree = MerkleTree::build(&["a", "b", "c"]);
+        let (result, output) = run_with(&["root"], "a\nb\nc");
+        assert_eq!(result, Ok(true));
+        assert_eq!(output, format!("{}\n", t.root_hash_str()));
+
+        let t: MerkleTree =
+            MerkleTree::build_with_mode(&["ab", "c"], DefaultHasher::new(), TreeMode::RFC6962);
+        let (_, output) = run_with(&["root", "-m", "rfc6962", "-c", "2", "-f", "json"], "abc");
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(json["root"], t.root_hash_str());
+        assert_eq!(json["leaves"], 2);
+        assert_eq!(json["mode"], "rfc6962");
+    }
+
+    #[test]
+    fn test_proofs_printed_by_prove_verify() {
+        let lines = "a\nb\nc\nd\ne";
+        let (_, root) = run_with(&["root", "-a", "sha512"], lines);
+
+        let (_, proof) = run_with(&["prove", "-a", "sha512", "-i", "3"], lines);
+        let path = temp_path("proof.hex");
+        fs::write(&path, &proof).unwrap();
+        let verify = [
+            "verify",
+            "-a",
+            "sha512",
+            "--proof",
+            &path,
+            "--root",
+            root.trim(),
+            "-n",
+            "5",
+        ];
+        let (result, output) = run_with(&[&verify[..], &["-i", "3", "--leaf", "d"]].concat(), "");
+        assert_eq!((result, output.as_str()), (Ok(true), "valid\n"));
+        let (result, _) = run_with(&[&verify[..], &["-i", "3", "--leaf", "x"]].concat(), "");
+        assert_eq!(result, Ok(false));
+        let (result, _) = run_with(&[&verify[..], &["-i", "2", "--leaf", "d"]].concat(), "");
+        assert_eq!(result, Ok(false));
+        let args = [&verify[..], &["-i", "3", "--leaf", "d", "-n", "6"]].concat();
+        assert_eq!(run_with(&args, "").0, Ok(false));
+        fs::remove_file(&path).unwrap();
+
+        let (_, root) = run_with(&["root", "-a", "blake3"], lines);
+        let (_, proof) = run_with(&["prove", "-a", "blake3", "-i", "1", "-f", "json"], lines);
+        let verify = ["verify", "--proof", "-", "--root", root.trim(), "-n", "5"];
+        let (result, _) = run_with(&[&verify[..], &["--leaf", "b"]].concat(), &proof);
+        assert_eq!(result, Ok(true));
+        let (result, _) = run_with(&[&verify[..], &["--leaf", "c"]].concat(), &proof);
+        assert_eq!(result, Ok(false));
+        let args = [&verify[..], &["--leaf", "b", "-m", "rfc6962"]].concat();
+        assert!(run_with(&args, &proof).0.is_err());
+    }
+
+    #[test]
+    fn test_verify_does_not_take_the_root_from_the_proof() {
+        let lines = "a\nb";
+        let (_, proof) = run_with(&["prove", "-i", "0", "-f", "json"], lines);
+        let args = ["verify", "--proof", "-", "-n", "2", "--leaf", "a"];
+        assert!(run_with(&args, &proof).0.is_err());
+
+        let (_, forged) = run_with(&["prove", "-i", "0", "-f", "json"], "a\nx");
+        let (_, root) = run_with(&["root"], lines);
+        let args = [&args[..], &["--root", root.trim()]].concat();
+        assert_eq!(run_with(&args, &proof).0, Ok(true));
+        assert_eq!(run_with(&args, &forged).0, Ok(false));
+    }
+
+    #[test]
+    fn test_diff_of_saved_trees() {
+        let left = temp_path("left.tree");
+        let right = temp_path("right.tree");
+        run_with(&["root", "--save", &left], "a\nb\nc\nd\ne\nf")
+            .0
+            .unwrap();
+        run_with(&["root", "--save", &right], "a\nx\ny\nd\ne\nf\ng")
+            .0
+            .unwrap();
+
+        let (result, output) = run_with(&["diff", &left, &right], "");
+        assert_eq!((result, output.as_str()), (Ok(false), "1..3\n6..7\n"));
+        let (result, output) = run_with(&["diff", "-f", "json", &left, &left], "");
+        assert_eq!(result, Ok(true));
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(json["equal"], true);
+
+        let (result, _) = run_with(&["diff", "-a", "blake3", &left, &right], "");
+        assert!(result.is_err());
+        fs::remove_file(&left).unwrap();
+        fs::remove_file(&right).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_arguments_are_reported() {
+        for args in [
+            &["frobnicate"][..],
+            &["root", "--bogus"],
+            &["root", "-c", "0"],
+            &["root", "-a", "md5"],
+            &["root", "-m", "sideways"],
+            &["prove"],
+            &["prove", "-i", "5"],
+            &["verify", "--proof", "-"],
+            &["verify", "--proof", "-", "--leaf", "a"],
+            &["verify", "--proof", "-", "--leaf", "a", "-n", "2"],
+            &["diff", "one"],
+        ]
+        .iter()
+        {
+            assert!(run_with(args, "a\nb").0.is_err(), "{:?}", args);
+        }
+        assert_eq!(run_with(&["help"], "").0, Ok(true));
+    }
+}
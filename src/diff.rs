@@ -0,0 +1,224 @@
+// Comparison of two trees
+use digest::{Digest, FixedOutputReset};
+use std::ops::Range;
+
+use super::tree::level_widths;
+use super::{MerkleError, MerkleTree};
+
+/// Leaf positions at which two trees differ, as returned by
+/// `MerkleTree::diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeDiff {
+    ranges: Vec<Range<usize>>,
+    comparisons: usize,
+}
+
+impl TreeDiff {
+    /// Disjoint ranges of positions in increasing order, including those of
+    /// the leaves only one of the trees has.
+    pub fn ranges(&self) -> &[Range<usize>] {
+        &self.ranges
+    }
+
+    /// Number of pairs of node hashes compared to find the ranges.
+    pub fn comparisons(&self) -> usize {
+        self.comparisons
+    }
+
+    /// Whether the trees have the same leaves.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Appends `range`, merging it with the last range if they touch.
+    fn push_range(&mut self, range: Range<usize>) {
+        match self.ranges.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => self.ranges.push(range),
+        }
+    }
+}
+
+impl<H> MerkleTree<H>
+where
+    H: Digest + FixedOutputReset,
+{
+    /// Finds the leaf positions at which this tree and `other` differ,
+    /// descending only into the subtrees whose hashes differ.
+    ///
+    /// Two nodes at the same place are only compared if they cover the same
+    /// leaves in both trees. When the trees have different numbers of leaves,
+    /// the nodes over the end of the shorter one are not, and are descended
+    /// into without comparing them, while the leaves past that end are
+    /// reported as a whole.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the trees were built in different modes, see `try_diff`.
+    pub fn diff(&self, other: &MerkleTree<H>) -> TreeDiff {
+        self.try_diff(other).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Same as `diff`, but returns `MerkleError::ModeMismatch` for trees
+    /// built in different modes. Their nodes cannot be compared, and their
+    /// leaves may or may not be hashed alike.
+    pub fn try_diff(&self, other: &MerkleTree<H>) -> Result<TreeDiff, MerkleError> {
+        if self.mode != other.mode {
+            return Err(MerkleError::ModeMismatch {
+                expected: self.mode,
+                received: other.mode,
+            });
+        }
+        let mut diff = TreeDiff {
+            ranges: Vec::new(),
+            comparisons: 0,
+        };
+        let count_leaves = self.count_leaves.max(other.count_leaves);
+        if count_leaves > 0 {
+            let height = level_widths(count_leaves).len() - 1;
+            let shared_height = level_widths(self.count_leaves.min(other.count_leaves)).len();
+            self.diff_node(other, height, 0, shared_height, &mut diff);
+        }
+        Ok(diff)
+    }
+
+    /// Compares the nodes at `index` of `level`, if both trees have them as
+    /// they are below `shared_height`, the height of the shorter tree.
+    fn diff_node(
+        &self,
+        other: &MerkleTree<H>,
+        level: usize,
+        index: usize,
+        shared_height: usize,
+        diff: &mut TreeDiff,
+    ) {
+        let start = index << level;
+        let full_end = (index + 1) << level;
+        let shared = self.count_leaves.min(other.count_leaves);
+        let count_leaves = self.count_leaves.max(other.count_leaves);
+        if start >= count_leaves {
+            return;
+        }
+        if start >= shared {
+            diff.push_range(start..full_end.min(count_leaves));
+            return;
+        }
+
+        if level < shared_height
+            && full_end.min(self.count_leaves) == full_end.min(other.count_leaves)
+        {
+            diff.comparisons += 1;
+            if self.node(level, index) == other.node(level, index) {
+                return;
+            }
+        }
+        if level == 0 {
+            diff.push_range(start..start + 1);
+            return;
+        }
+        self.diff_node(other, level - 1, 2 * index, shared_height, diff);
+        self.diff_node(other, level - 1, 2 * index + 1, shared_height, diff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{DefaultHasher, MerkleError, MerkleTree, TreeMode};
+
+    const MODES: [TreeMode; 4] = [
+        TreeMode::DEFAULT,
+        TreeMode::RFC6962,
+        TreeMode::BITCOIN,
+        TreeMode::BLAKE3,
+    ];
+
+    fn build(values: &[String], mode: TreeMode) -> MerkleTree {
+        MerkleTree::build_with_mode(values, DefaultHasher::new(), mode)
+    }
+
+    /// Ranges found by comparing every leaf.
+    fn expected_ranges(left: &[String], right: &[String]) -> Vec<std::ops::Range<usize>> {
+        let mut ranges: Vec<std::ops::Range<usize>> = Vec::new();
+        for position in 0..left.len().max(right.len()) {
+            if left.get(position) == right.get(position) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(range) if range.end == position => range.end += 1,
+                _ => ranges.push(position..position + 1),
+            }
+        }
+        ranges
+    }
+
+    #[test]
+    fn test_equal_trees_take_one_comparison() {
+        let values: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        for &mode in MODES.iter() {
+            let diff = build(&values, mode).diff(&build(&values, mode));
+            assert!(diff.is_empty());
+            assert_eq!(diff.comparisons(), 1);
+        }
+        let empty: MerkleTree = MerkleTree::build::<&str>(&[]);
+        assert!(empty.diff(&empty).is_empty());
+    }
+
+    #[test]
+    fn test_changed_leaves_are_located() {
+        let values: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        let mut changed = values.clone();
+        changed[7] = "x".to_string();
+        changed[8] = "y".to_string();
+        changed[63] = "z".to_string();
+        for &mode in MODES.iter() {
+            let diff = build(&values, mode).diff(&build(&changed, mode));
+            assert_eq!(diff.ranges(), &[7..9, 63..64]);
+            assert!(diff.comparisons() < 50);
+        }
+    }
+
+    #[test]
+    fn test_trees_built_in_different_modes_are_not_compared() {
+        let values = ["a", "b", "c"];
+        let default: MerkleTree =
+            MerkleTree::build_with_mode(&values, DefaultHasher::new(), TreeMode::DEFAULT);
+        let rfc6962: MerkleTree =
+            MerkleTree::build_with_mode(&values, DefaultHasher::new(), TreeMode::RFC6962);
+        assert_eq!(
+            default.try_diff(&rfc6962).unwrap_err(),
+            MerkleError::ModeMismatch {
+                expected: TreeMode::DEFAULT,
+                received: TreeMode::RFC6962
+            }
+        );
+
+        let longer: MerkleTree = MerkleTree::build_with_mode(
+            &["a", "b", "c", "d", "e"],
+            DefaultHasher::new(),
+            TreeMode::BITCOIN,
+        );
+        assert!(longer.try_diff(&default).is_err());
+        assert!(longer.try_diff(&longer).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_appended_and_removed_leaves_are_reported() {
+        let values: Vec<String> = (0..40).map(|i| i.to_string()).collect();
+        for &mode in MODES.iter() {
+            for left_len in 0..values.len() {
+                for &right_len in [0, 1, left_len, left_len + 1, 33, 40].iter() {
+                    let mut right = values[..right_len].to_vec();
+                    if right_len > 5 {
+                        right[5] = "x".to_string();
+                    }
+                    let left = &values[..left_len];
+                    let diff = build(left, mode).diff(&build(&right, mode));
+                    assert_eq!(diff.ranges(), expected_ranges(left, &right).as_slice());
+
+                    let diff = build(&right, mode).diff(&build(left, mode));
+                    assert_eq!(diff.ranges(), expected_ranges(&right, left).as_slice());
+                }
+            }
+        }
+    }
+}
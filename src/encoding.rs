@@ -0,0 +1,745 @@
+// Binary encoding of trees and proofs
+//
+// Version 1 of the format. Integers are little endian.
+//
+//   magic       4 bytes   "MRKL"
+//   version     1 byte    1
+//   kind        1 byte    1 tree, 2 inclusion proof, 3 multiproof,
+//                         4 consistency proof, 5 sparse proof
+//   algorithm   1 byte    `HashAlgorithm::ID` of the hasher of a tree, 0 for
+//                         proofs, which do not record it
+//   mode        1 byte    bit 0 set for `OddNodePolicy::Promote`, bit 1 for
+//                         domain separation, bit 2 for `Chaining::Blake3`
+//   hash length 1 byte
+//   leaves      8 bytes   number of leaves, 0 for consistency proofs
+//   hashes      8 bytes   number of hashes that follow
+//   bitmap      32 bytes  only for sparse proofs
+//   ...         hashes * hash length bytes
+//
+// A tree is followed by all of its nodes level by level, from the leaves up
+// to the root and left to right within a level, without padding duplicates.
+// An empty tree only has its root. Proofs are followed by their hashes in the
+// order of `path` or `hashes`. Sparse proofs are written in the mode of
+// their hashing, `TreeMode::DEFAULT`, with no leaves and their bitmap, and
+// are followed by the siblings that are not default hashes.
+use blake2::{Blake2b512, Blake2s256};
+use digest::{Digest, FixedOutputReset, Output};
+use sha2::{Sha224, Sha256, Sha384, Sha512, Sha512_224, Sha512_256};
+use sha3::{Keccak256, Sha3_256};
+
+use super::bitcoin::DoubleSha256;
+use super::blake3::Blake3;
+use super::sparse::SparseProof;
+use super::tree::{_build_from_leaves_with_hasher, build_upper_level, level_widths};
+use super::{
+    Chaining, ConsistencyProof, DefaultHasher, Hash, InclusionProof, MerkleError, MerkleTree,
+    MultiProof, OddNodePolicy, TreeMode,
+};
+
+const MAGIC: [u8; 4] = *b"MRKL";
+pub const FORMAT_VERSION: u8 = 1;
+
+const KIND_TREE: u8 = 1;
+const KIND_INCLUSION_PROOF: u8 = 2;
+const KIND_MULTIPROOF: u8 = 3;
+const KIND_CONSISTENCY_PROOF: u8 = 4;
+const KIND_SPARSE_PROOF: u8 = 5;
+
+const SPARSE_BITMAP_LEN: usize = 32;
+
+const MODE_PROMOTE: u8 = 1;
+const MODE_DOMAIN_SEPARATION: u8 = 2;
+const MODE_BLAKE3: u8 = 4;
+
+const HEADER_LEN: usize = 4 + 1 + 1 + 1 + 1 + 1 + 8 + 8;
+
+/// Hash function with a number identifying it in encoded trees, so that a
+/// tree is never decoded with another hash function than it was built with.
+///
+/// | ID | Hash function                 |
+/// |----|-------------------------------|
+/// | 1  | SHA-256, also `DefaultHasher` |
+/// | 2  | SHA-224                       |
+/// | 3  | SHA-384                       |
+/// | 4  | SHA-512                       |
+/// | 5  | SHA-512/224                   |
+/// | 6  | SHA-512/256                   |
+/// | 7  | `bitcoin::DoubleSha256`       |
+/// | 8  | `blake3::Blake3`              |
+/// | 9  | SHA3-256                      |
+/// | 10 | Keccak-256                    |
+/// | 11 | BLAKE2b-512                   |
+/// | 12 | BLAKE2s-256                   |
+pub trait HashAlgorithm: Digest + FixedOutputReset {
+    const ID: u8;
+}
+
+impl HashAlgorithm for DefaultHasher {
+    const ID: u8 = 1;
+}
+
+impl HashAlgorithm for Sha256 {
+    const ID: u8 = 1;
+}
+
+impl HashAlgorithm for Sha224 {
+    const ID: u8 = 2;
+}
+
+impl HashAlgorithm for Sha384 {
+    const ID: u8 = 3;
+}
+
+impl HashAlgorithm for Sha512 {
+    const ID: u8 = 4;
+}
+
+impl HashAlgorithm for Sha512_224 {
+    const ID: u8 = 5;
+}
+
+impl HashAlgorithm for Sha512_256 {
+    const ID: u8 = 6;
+}
+
+impl HashAlgorithm for DoubleSha256 {
+    const ID: u8 = 7;
+}
+
+impl HashAlgorithm for Blake3 {
+    const ID: u8 = 8;
+}
+
+impl HashAlgorithm for Sha3_256 {
+    const ID: u8 = 9;
+}
+
+impl HashAlgorithm for Keccak256 {
+    const ID: u8 = 10;
+}
+
+impl HashAlgorithm for Blake2b512 {
+    const ID: u8 = 11;
+}
+
+impl HashAlgorithm for Blake2s256 {
+    const ID: u8 = 12;
+}
+
+struct Header {
+    kind: u8,
+    algorithm: u8,
+    mode: TreeMode,
+    hash_len: usize,
+    count_leaves: usize,
+    count_hashes: usize,
+}
+
+impl<H> MerkleTree<H>
+where
+    H: HashAlgorithm,
+{
+    /// Encodes the tree in version `FORMAT_VERSION` of the binary format.
+    /// Fails if the hashes of `H` are longer than 255 bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MerkleError> {
+        let widths = level_widths(self.count_leaves);
+        let count_hashes = if self.count_leaves == 0 {
+            1
+        } else {
+            widths.iter().sum()
+        };
+
+        let header = Header {
+            kind: KIND_TREE,
+            algorithm: H::ID,
+            mode: self.mode,
+            hash_len: <H as Digest>::output_size(),
+            count_leaves: self.count_leaves,
+            count_hashes,
+        };
+        let mut bytes = header.to_bytes()?;
+        if self.count_leaves == 0 {
+            bytes.extend_from_slice(&self.root);
+        } else {
+            for (level, &width) in widths.iter().enumerate() {
+                for index in 0..width {
+                    bytes.extend_from_slice(&self.try_node(level, index)?);
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Decodes a tree encoded by `to_bytes`. The internal nodes are rebuilt
+    /// from the leaves and must all match the encoded ones.
+    pub fn from_bytes(bytes: &[u8]) -> Result<MerkleTree<H>, MerkleError>
+    where
+        H: Default,
+    {
+        MerkleTree::from_bytes_with_hasher(bytes, Default::default())
+    }
+
+    pub fn from_bytes_with_hasher(bytes: &[u8], hasher: H) -> Result<MerkleTree<H>, MerkleError> {
+        let (header, hashes) = Header::from_bytes(bytes, KIND_TREE)?;
+        if header.algorithm != H::ID {
+            return Err(MerkleError::AlgorithmMismatch {
+                expected: H::ID,
+                received: header.algorithm,
+            });
+        }
+        if header.hash_len != <H as Digest>::output_size() {
+            return Err(MerkleError::Malformed(
+                "hash length does not match the hasher",
+            ));
+        }
+
+        let widths = level_widths(header.count_leaves);
+        let expected_hashes = if header.count_leaves == 0 {
+            1
+        } else {
+            widths.iter().sum()
+        };
+        if header.count_hashes != expected_hashes {
+            return Err(MerkleError::Malformed(
+                "number of hashes does not match the number of leaves",
+            ));
+        }
+
+        let nodes: Vec<Output<H>> = hashes
+            .chunks(header.hash_len)
+            .map(Output::<H>::clone_from_slice)
+            .collect();
+        let tree = _build_from_leaves_with_hasher(
+            &nodes[..header.count_leaves],
+            hasher,
+            header.mode,
+            build_upper_level,
+        )?;
+
+        let consistent = if header.count_leaves == 0 {
+            tree.root == nodes[0]
+        } else {
+            let mut encoded = nodes.iter();
+            widths.iter().enumerate().all(|(level, &width)| {
+                (0..width).all(|index| Some(&tree.node(level, index)) == encoded.next())
+            })
+        };
+        if !consistent {
+            return Err(MerkleError::Malformed(
+                "node hash does not match the hash of its children",
+            ));
+        }
+        Ok(tree)
+    }
+}
+
+impl InclusionProof {
+    /// Encodes the proof in version `FORMAT_VERSION` of the binary format.
+    /// Fails if the hashes of the proof differ in length or are longer than
+    /// 255 bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MerkleError> {
+        encode_proof(
+            KIND_INCLUSION_PROOF,
+            self.mode(),
+            self.count_leaves(),
+            self.path(),
+        )
+    }
+
+    /// Decodes a proof encoded by `to_bytes`, which has to be verified still.
+    pub fn from_bytes(bytes: &[u8]) -> Result<InclusionProof, MerkleError> {
+        let (header, path) = decode_proof(bytes, KIND_INCLUSION_PROOF)?;
+        if header.count_leaves == 0 {
+            return Err(MerkleError::Malformed("proof for a tree without leaves"));
+        }
+        if path.len() >= level_widths(header.count_leaves).len() {
+            return Err(MerkleError::Malformed("path longer than the tree is high"));
+        }
+        Ok(InclusionProof::new(header.mode, header.count_leaves, path))
+    }
+}
+
+impl MultiProof {
+    /// Encodes the proof in version `FORMAT_VERSION` of the binary format.
+    /// Fails if the hashes of the proof differ in length or are longer than
+    /// 255 bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MerkleError> {
+        encode_proof(
+            KIND_MULTIPROOF,
+            self.mode(),
+            self.count_leaves(),
+            self.hashes(),
+        )
+    }
+
+    /// Decodes a proof encoded by `to_bytes`, which has to be verified still.
+    pub fn from_bytes(bytes: &[u8]) -> Result<MultiProof, MerkleError> {
+        let (header, hashes) = decode_proof(bytes, KIND_MULTIPROOF)?;
+        if header.count_leaves == 0 {
+            return Err(MerkleError::Malformed("proof for a tree without leaves"));
+        }
+        let count_nodes = level_widths(header.count_leaves)
+            .iter()
+            .fold(0usize, |sum, &width| sum.saturating_add(width));
+        if hashes.len() >= count_nodes {
+            return Err(MerkleError::Malformed(
+                "more hashes than the tree has nodes",
+            ));
+        }
+        Ok(MultiProof::new(header.mode, header.count_leaves, hashes))
+    }
+}
+
+impl ConsistencyProof {
+    /// Encodes the proof in version `FORMAT_VERSION` of the binary format.
+    /// Fails if the hashes of the proof differ in length or are longer than
+    /// 255 bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MerkleError> {
+        encode_proof(KIND_CONSISTENCY_PROOF, self.mode(), 0, self.hashes())
+    }
+
+    /// Decodes a proof encoded by `to_bytes`, which has to be verified still.
+    pub fn from_bytes(bytes: &[u8]) -> Result<ConsistencyProof, MerkleError> {
+        let (header, hashes) = decode_proof(bytes, KIND_CONSISTENCY_PROOF)?;
+        if header.count_leaves != 0 {
+            return Err(MerkleError::Malformed(
+                "consistency proofs do not record a number of leaves",
+            ));
+        }
+        Ok(ConsistencyProof::new(header.mode, hashes))
+    }
+}
+
+impl SparseProof {
+    /// Encodes the proof in version `FORMAT_VERSION` of the binary format.
+    /// Fails if the hashes of the proof differ in length or are longer than
+    /// 255 bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MerkleError> {
+        let mut bytes = encode_proof(KIND_SPARSE_PROOF, TreeMode::DEFAULT, 0, self.siblings())?;
+        let hashes = bytes.split_off(HEADER_LEN);
+        bytes.extend_from_slice(self.bitmap());
+        bytes.extend_from_slice(&hashes);
+        Ok(bytes)
+    }
+
+    /// Decodes a proof encoded by `to_bytes`, which has to be verified still.
+    pub fn from_bytes(bytes: &[u8]) -> Result<SparseProof, MerkleError> {
+        let (header, bitmap, siblings) = decode_proof_with_bitmap(bytes)?;
+        if header.mode != TreeMode::DEFAULT || header.count_leaves != 0 {
+            return Err(MerkleError::Malformed(
+                "sparse proofs have a fixed mode and no leaves",
+            ));
+        }
+        let count_set: u32 = bitmap.iter().map(|byte| byte.count_ones()).sum();
+        if count_set as usize != siblings.len() {
+            return Err(MerkleError::Malformed(
+                "bitmap does not match the number of siblings",
+            ));
+        }
+        Ok(SparseProof::new(bitmap, siblings))
+    }
+}
+
+fn encode_proof(
+    kind: u8,
+    mode: TreeMode,
+    count_leaves: usize,
+    hashes: &[Hash],
+) -> Result<Vec<u8>, MerkleError> {
+    let hash_len = hashes.first().map_or(0, |h| h.len());
+    if hashes.iter().any(|h| h.len() != hash_len) {
+        return Err(MerkleError::Malformed(
+            "hashes of a proof must all have the same length",
+        ));
+    }
+
+    let header = Header {
+        kind,
+        algorithm: 0,
+        mode,
+        hash_len,
+        count_leaves,
+        count_hashes: hashes.len(),
+    };
+    let mut bytes = header.to_bytes()?;
+    for hash in hashes {
+        bytes.extend_from_slice(hash);
+    }
+    Ok(bytes)
+}
+
+/// Same as `decode_proof` for sparse proofs, whose bitmap sits between the
+/// header and the hashes.
+fn decode_proof_with_bitmap(
+    bytes: &[u8],
+) -> Result<(Header, [u8; SPARSE_BITMAP_LEN], Vec<Hash>), MerkleError> {
+    if bytes.len() < HEADER_LEN + SPARSE_BITMAP_LEN {
+        return Err(MerkleError::Malformed("unexpected end of input"));
+    }
+    let (header, rest) = bytes.split_at(HEADER_LEN);
+    let mut rest = rest;
+    let bitmap = read_array(&mut rest)?;
+    let (header, hashes) = decode_proof(&[header, rest].concat(), KIND_SPARSE_PROOF)?;
+    Ok((header, bitmap, hashes))
+}
+
+fn decode_proof(bytes: &[u8], kind: u8) -> Result<(Header, Vec<Hash>), MerkleError> {
+    let (header, hashes) = Header::from_bytes(bytes, kind)?;
+    if header.algorithm != 0 {
+        return Err(MerkleError::Malformed(
+            "proofs do not record a hash algorithm",
+        ));
+    }
+    if header.hash_len == 0 && header.count_hashes > 0 {
+        return Err(MerkleError::Malformed("empty hashes"));
+    }
+    let hashes = hashes
+        .chunks(header.hash_len.max(1))
+        .map(Hash::from)
+        .collect();
+    Ok((header, hashes))
+}
+
+impl Header {
+    fn to_bytes(&self) -> Result<Vec<u8>, MerkleError> {
+        if self.hash_len > u8::MAX as usize {
+            return Err(MerkleError::Malformed("hashes are too long"));
+        }
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.count_hashes * self.hash_len);
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(FORMAT_VERSION);
+        bytes.push(self.kind);
+        bytes.push(self.algorithm);
+        bytes.push(encode_mode(self.mode));
+        bytes.push(self.hash_len as u8);
+        bytes.extend_from_slice(&(self.count_leaves as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.count_hashes as u64).to_le_bytes());
+        Ok(bytes)
+    }
+
+    /// Reads the header and returns it along with the bytes of the hashes,
+    /// which are exactly `count_hashes` times `hash_len` long.
+    fn from_bytes(bytes: &[u8], kind: u8) -> Result<(Header, &[u8]), MerkleError> {
+        let mut input = bytes;
+        if read_array(&mut input)? != MAGIC {
+            return Err(MerkleError::Malformed("not an encoded tree or proof"));
+        }
+        let [version, received_kind, algorithm, mode, hash_len] = read_array(&mut input)?;
+        if version != FORMAT_VERSION {
+            return Err(MerkleError::UnsupportedVersion(version));
+        }
+        if received_kind != kind {
+            return Err(MerkleError::Malformed("unexpected kind of structure"));
+        }
+        let mode = decode_mode(mode)?;
+        let count_leaves = read_length(&mut input)?;
+        let count_hashes = read_length(&mut input)?;
+
+        let hash_len = hash_len as usize;
+        let hashes_len = count_hashes
+            .checked_mul(hash_len)
+            .ok_or(MerkleError::Malformed("truncated hashes"))?;
+        if hashes_len > input.len() {
+            return Err(MerkleError::Malformed("truncated hashes"));
+        }
+        if hashes_len < input.len() {
+            return Err(MerkleError::Malformed("trailing bytes"));
+        }
+        if count_leaves > count_hashes && kind == KIND_TREE {
+            return Err(MerkleError::Malformed("truncated hashes"));
+        }
+
+        let header = Header {
+            kind,
+            algorithm,
+            mode,
+            hash_len,
+            count_leaves,
+            count_hashes,
+        };
+        Ok((header, input))
+    }
+}
+
+pub(crate) fn encode_mode(mode: TreeMode) -> u8 {
+    let mut byte = 0;
+    if mode.odd_node_policy == OddNodePolicy::Promote {
+        byte |= MODE_PROMOTE;
+    }
+    if mode.domain_separation {
+        byte |= MODE_DOMAIN_SEPARATION;
+    }
+    if mode.chaining == Chaining::Blake3 {
+        byte |= MODE_BLAKE3;
+    }
+    byte
+}
+
+pub(crate) fn decode_mode(byte: u8) -> Result<TreeMode, MerkleError> {
+    if byte & !(MODE_PROMOTE | MODE_DOMAIN_SEPARATION | MODE_BLAKE3) != 0 {
+        return Err(MerkleError::Malformed("unknown mode bits"));
+    }
+    Ok(TreeMode {
+        odd_node_policy: if byte & MODE_PROMOTE != 0 {
+            OddNodePolicy::Promote
+        } else {
+            OddNodePolicy::Duplicate
+        },
+        domain_separation: byte & MODE_DOMAIN_SEPARATION != 0,
+        chaining: if byte & MODE_BLAKE3 != 0 {
+            Chaining::Blake3
+        } else {
+            Chaining::Digest
+        },
+    })
+}
+
+pub(crate) fn read_length(input: &mut &[u8]) -> Result<usize, MerkleError> {
+    usize::try_from(u64::from_le_bytes(read_array(input)?))
+        .map_err(|_| MerkleError::Malformed("length does not fit in memory"))
+}
+
+pub(crate) fn read_array<const N: usize>(input: &mut &[u8]) -> Result<[u8; N], MerkleError> {
+    if input.len() < N {
+        return Err(MerkleError::Malformed("unexpected end of input"));
+    }
+    let (head, rest) = input.split_at(N);
+    *input = rest;
+    let mut array = [0u8; N];
+    array.copy_from_slice(head);
+    Ok(array)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::bitcoin::DoubleSha256;
+    use super::super::blake3::Blake3;
+    use super::super::{
+        ConsistencyProof, DefaultHasher, Hash, InclusionProof, MerkleError, MerkleTree, MultiProof,
+        SparseMerkleTree, SparseProof, TreeMode,
+    };
+    use super::{HashAlgorithm, HEADER_LEN};
+
+    const MODES: [TreeMode; 4] = [
+        TreeMode::DEFAULT,
+        TreeMode::RFC6962,
+        TreeMode::BITCOIN,
+        TreeMode::BLAKE3,
+    ];
+
+    fn build(count: usize, mode: TreeMode) -> MerkleTree {
+        let values: Vec<String> = (0..count).map(|i| i.to_string()).collect();
+        MerkleTree::build_with_mode(&values, DefaultHasher::new(), mode)
+    }
+
+    #[test]
+    fn test_tree_round_trip() {
+        for &mode in MODES.iter() {
+            for count in 0..20 {
+                let t = build(count, mode);
+                let bytes = t.to_bytes().unwrap();
+                let decoded: MerkleTree = MerkleTree::from_bytes(&bytes).unwrap();
+                assert_eq!(decoded.mode(), mode);
+                assert_eq!(decoded.leaves(), t.leaves());
+                assert_eq!(decoded.root_hash(), t.root_hash());
+                assert_eq!(decoded.to_bytes().unwrap(), bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tree_grown_by_pushing_round_trips() {
+        let mut t = build(0, TreeMode::DEFAULT);
+        for i in 0..9 {
+            t.push(&i.to_string());
+        }
+        let decoded: MerkleTree = MerkleTree::from_bytes(&t.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.root_hash(), build(9, TreeMode::DEFAULT).root_hash());
+    }
+
+    #[test]
+    fn test_header_layout() {
+        let bytes = build(3, TreeMode::RFC6962).to_bytes().unwrap();
+        assert_eq!(&bytes[..4], b"MRKL");
+        assert_eq!(&bytes[4..9], &[1, 1, 1, 3, 32]);
+        assert_eq!(&bytes[9..17], &3u64.to_le_bytes());
+        assert_eq!(&bytes[17..25], &6u64.to_le_bytes());
+        assert_eq!(bytes.len(), HEADER_LEN + 6 * 32);
+    }
+
+    #[test]
+    fn test_tampered_tree_is_rejected() {
+        let bytes = build(5, TreeMode::DEFAULT).to_bytes().unwrap();
+        for i in HEADER_LEN..bytes.len() {
+            let mut tampered = bytes.clone();
+            tampered[i] ^= 1;
+            assert_eq!(
+                MerkleTree::<DefaultHasher>::from_bytes(&tampered).unwrap_err(),
+                MerkleError::Malformed("node hash does not match the hash of its children")
+            );
+        }
+
+        let empty = build(0, TreeMode::DEFAULT).to_bytes().unwrap();
+        let mut tampered = empty.clone();
+        tampered[HEADER_LEN] ^= 1;
+        assert!(MerkleTree::<DefaultHasher>::from_bytes(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_malformed_headers_are_rejected() {
+        let bytes = build(5, TreeMode::DEFAULT).to_bytes().unwrap();
+
+        let mut version = bytes.clone();
+        version[4] = 2;
+        assert_eq!(
+            MerkleTree::<DefaultHasher>::from_bytes(&version).unwrap_err(),
+            MerkleError::UnsupportedVersion(2)
+        );
+
+        assert_eq!(
+            MerkleTree::<DoubleSha256>::from_bytes(&bytes).unwrap_err(),
+            MerkleError::AlgorithmMismatch {
+                expected: 7,
+                received: 1
+            }
+        );
+
+        let mut mode = bytes.clone();
+        mode[7] |= 0x80;
+        assert!(MerkleTree::<DefaultHasher>::from_bytes(&mode).is_err());
+
+        let mut leaves = bytes.clone();
+        leaves[9] = 4;
+        assert!(MerkleTree::<DefaultHasher>::from_bytes(&leaves).is_err());
+
+        for len in 0..bytes.len() {
+            assert!(MerkleTree::<DefaultHasher>::from_bytes(&bytes[..len]).is_err());
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(MerkleTree::<DefaultHasher>::from_bytes(&trailing).is_err());
+
+        assert!(InclusionProof::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_blake3_tree_round_trip() {
+        let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let chunks: Vec<&[u8]> = data.chunks(1024).collect();
+        let t: MerkleTree<Blake3> =
+            MerkleTree::build_with_mode(&chunks, Blake3::new(), TreeMode::BLAKE3);
+        let decoded: MerkleTree<Blake3> = MerkleTree::from_bytes(&t.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.root_hash(), t.root_hash());
+    }
+
+    /// Checks that a tree hashed with `H` round trips and is not decoded
+    /// with SHA-256.
+    fn check_round_trip<H>()
+    where
+        H: HashAlgorithm + Default,
+    {
+        let values: Vec<String> = (0..11).map(|i| i.to_string()).collect();
+        let t: MerkleTree<H> = MerkleTree::build(&values);
+        let bytes = t.to_bytes().unwrap();
+        assert_eq!(bytes[6], H::ID);
+        let decoded: MerkleTree<H> = MerkleTree::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.root_hash(), t.root_hash());
+        assert_eq!(
+            MerkleTree::<DefaultHasher>::from_bytes(&bytes).unwrap_err(),
+            MerkleError::AlgorithmMismatch {
+                expected: 1,
+                received: H::ID
+            }
+        );
+    }
+
+    #[test]
+    fn test_sha3_and_blake2_trees_round_trip() {
+        check_round_trip::<sha3::Sha3_256>();
+        check_round_trip::<sha3::Keccak256>();
+        check_round_trip::<blake2::Blake2b512>();
+        check_round_trip::<blake2::Blake2s256>();
+    }
+
+    #[test]
+    fn test_proofs_round_trip() {
+        for &mode in MODES.iter() {
+            let t = build(11, mode);
+            let mut hasher = DefaultHasher::new();
+            for position in 0..11 {
+                let proof =
+                    InclusionProof::from_bytes(&t.prove(position).to_bytes().unwrap()).unwrap();
+                assert_eq!(proof, t.prove(position));
+                let value = position.to_string();
+                assert!(proof.verify(t.root_hash(), 11, position, &value, mode, &mut hasher));
+            }
+
+            let multi =
+                MultiProof::from_bytes(&t.prove_many(&[0, 4, 10]).to_bytes().unwrap()).unwrap();
+            assert_eq!(multi, t.prove_many(&[0, 4, 10]));
+
+            for old_size in 0..12 {
+                let proof = t.consistency_proof(old_size);
+                assert_eq!(
+                    ConsistencyProof::from_bytes(&proof.to_bytes().unwrap()).unwrap(),
+                    proof
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed_proofs_are_rejected() {
+        let t = build(4, TreeMode::DEFAULT);
+        let mut bytes = t.prove(1).to_bytes().unwrap();
+        bytes[9] = 2;
+        assert!(InclusionProof::from_bytes(&bytes).is_err());
+
+        let bytes = t.prove(1).to_bytes().unwrap();
+        assert!(MultiProof::from_bytes(&bytes).is_err());
+        assert!(InclusionProof::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_proofs_with_hashes_of_different_lengths_are_not_encoded() {
+        let hashes = vec![Hash::from(vec![1; 32]), Hash::from(vec![2; 20])];
+        let proof = InclusionProof::new(TreeMode::DEFAULT, 4, hashes.clone());
+        assert!(proof.to_bytes().is_err());
+        assert!(MultiProof::new(TreeMode::DEFAULT, 4, hashes.clone())
+            .to_bytes()
+            .is_err());
+        assert!(ConsistencyProof::new(TreeMode::DEFAULT, hashes)
+            .to_bytes()
+            .is_err());
+        let long = vec![Hash::from(vec![1; 256])];
+        assert!(InclusionProof::new(TreeMode::DEFAULT, 2, long)
+            .to_bytes()
+            .is_err());
+    }
+
+    #[test]
+    fn test_sparse_proofs_round_trip() {
+        let mut t = SparseMerkleTree::<DefaultHasher>::new();
+        for n in 0..20u8 {
+            t.insert([n; 32], &"value");
+        }
+        let mut hasher = DefaultHasher::new();
+        for key in [[3u8; 32], [100u8; 32]].iter() {
+            let proof = t.prove(key);
+            let bytes = proof.to_bytes().unwrap();
+            assert_eq!(bytes.len(), HEADER_LEN + 32 + proof.siblings().len() * 32);
+            let decoded = SparseProof::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded, proof);
+            assert!(
+                decoded.verify(t.root_hash(), key, &"value", &mut hasher)
+                    || decoded.verify_non_inclusion(t.root_hash(), key, &mut hasher)
+            );
+
+            let mut tampered = bytes.clone();
+            tampered[HEADER_LEN] ^= 1;
+            assert!(SparseProof::from_bytes(&tampered).is_err());
+            assert!(SparseProof::from_bytes(&bytes[..HEADER_LEN + 31]).is_err());
+        }
+    }
+}
@@ -0,0 +1,133 @@
+// Merkle Tree errors
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use super::TreeMode;
+
+/// Error returned by the fallible `try_*` methods of `MerkleTree`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MerkleError {
+    /// A position does not relate to any leaf of the tree.
+    PositionOutOfRange {
+        position: usize,
+        count_leaves: usize,
+    },
+    /// A supplied leaf hash is not as long as the hasher output.
+    LeafHashLengthMismatch {
+        position: usize,
+        expected: usize,
+        received: usize,
+    },
+    /// A consistency proof was requested for an old size the tree never had.
+    OldSizeOutOfRange {
+        old_size: usize,
+        count_leaves: usize,
+    },
+    /// A value cannot be hashed as a BLAKE3 chunk, which holds 1 to
+    /// `blake3::CHUNK_LEN` bytes.
+    ChunkLengthOutOfRange { position: usize, received: usize },
+    /// BLAKE3 chaining was given hashes that are not 32-byte chaining values,
+    /// as a hasher with another output size produces.
+    ChainingValueLength { received: usize },
+    /// Encoded data was written in a version of the format this crate does
+    /// not know.
+    UnsupportedVersion(u8),
+    /// Encoded data was hashed with another algorithm than the one decoding
+    /// it, see `HashAlgorithm`.
+    AlgorithmMismatch { expected: u8, received: u8 },
+    /// Two trees or a tree and a peer cannot be compared because their hashes
+    /// were computed in different modes.
+    ModeMismatch {
+        expected: TreeMode,
+        received: TreeMode,
+    },
+    /// Encoded data or a proof structure does not describe a valid tree.
+    Malformed(&'static str),
+    /// A `NodeStore` failed to read or write a node.
+    Io {
+        kind: io::ErrorKind,
+        message: String,
+    },
+}
+
+impl fmt::Display for MerkleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MerkleError::PositionOutOfRange {
+                position,
+                count_leaves,
+            } => write!(
+                f,
+                "position {} does not relate to any leaf of a tree with {} leaves",
+                position, count_leaves
+            ),
+            MerkleError::LeafHashLengthMismatch {
+                position,
+                expected,
+                received,
+            } => write!(
+                f,
+                "leaf {} is {} bytes long, expected {} bytes",
+                position, received, expected
+            ),
+            MerkleError::OldSizeOutOfRange {
+                old_size,
+                count_leaves,
+            } => write!(
+                f,
+                "old size must be at most {}, received {}",
+                count_leaves, old_size
+            ),
+            MerkleError::ChunkLengthOutOfRange { position, received } => write!(
+                f,
+                "leaf {} is {} bytes long, BLAKE3 chunks hold 1 to {} bytes",
+                position,
+                received,
+                super::blake3::CHUNK_LEN
+            ),
+            MerkleError::ChainingValueLength { received } => write!(
+                f,
+                "BLAKE3 chaining values are 32 bytes long, received {} bytes",
+                received
+            ),
+            MerkleError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+            MerkleError::AlgorithmMismatch { expected, received } => write!(
+                f,
+                "hash algorithm {} does not match the expected algorithm {}",
+                received, expected
+            ),
+            MerkleError::ModeMismatch { expected, received } => write!(
+                f,
+                "tree mode {:?} does not match the expected mode {:?}",
+                received, expected
+            ),
+            MerkleError::Malformed(reason) => write!(f, "malformed input: {}", reason),
+            MerkleError::Io { ref message, .. } => write!(f, "node store: {}", message),
+        }
+    }
+}
+
+impl Error for MerkleError {}
+
+impl From<io::Error> for MerkleError {
+    fn from(error: io::Error) -> MerkleError {
+        MerkleError::Io {
+            kind: error.kind(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Errors of the node stores come back as the `io::Error` they were, others
+/// as `io::ErrorKind::InvalidData`.
+impl From<MerkleError> for io::Error {
+    fn from(error: MerkleError) -> io::Error {
+        match error {
+            MerkleError::Io { kind, message } => io::Error::new(kind, message),
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
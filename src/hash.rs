@@ -0,0 +1,252 @@
+// Hashes handed out by proofs
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+use subtle::ConstantTimeEq;
+
+use super::MerkleError;
+
+/// Hash of a node as carried by proofs and partial trees, as long as the
+/// output of the hasher it was computed with.
+///
+/// Hashes display and parse as lowercase hex. They compare in constant time,
+/// so that checking a hash against a secret one does not leak how many of
+/// their leading bytes match.
+#[derive(Clone, Default)]
+pub struct Hash(Vec<u8>);
+
+impl Hash {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0
+    }
+
+    pub fn to_base64(&self) -> String {
+        BASE64.encode(&self.0)
+    }
+
+    /// Parses standard base64 with padding.
+    pub fn from_base64(encoded: &str) -> Result<Hash, MerkleError> {
+        BASE64
+            .decode(encoded)
+            .map(Hash)
+            .map_err(|_| MerkleError::Malformed("invalid base64"))
+    }
+}
+
+/// Compares two byte strings without branching on their contents. Only their
+/// lengths, which are public for hashes, may influence the time taken.
+pub(crate) fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+impl PartialEq for Hash {
+    fn eq(&self, other: &Hash) -> bool {
+        ct_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Hash {}
+
+impl PartialOrd for Hash {
+    fn partial_cmp(&self, other: &Hash) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Orders hashes by their bytes, which is not constant time.
+impl Ord for Hash {
+    fn cmp(&self, other: &Hash) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hash({})", self)
+    }
+}
+
+/// Parses hex in either case. Anything but an even number of hex digits,
+/// including whitespace, is rejected.
+impl FromStr for Hash {
+    type Err = MerkleError;
+
+    fn from_str(hex: &str) -> Result<Hash, MerkleError> {
+        let digits = hex.as_bytes();
+        if !digits.len().is_multiple_of(2) {
+            return Err(MerkleError::Malformed("invalid hex"));
+        }
+        digits
+            .chunks(2)
+            .map(|pair| Ok(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+            .collect::<Result<Vec<u8>, MerkleError>>()
+            .map(Hash)
+    }
+}
+
+fn hex_digit(digit: u8) -> Result<u8, MerkleError> {
+    match digit {
+        b'0'..=b'9' => Ok(digit - b'0'),
+        b'a'..=b'f' => Ok(digit - b'a' + 10),
+        b'A'..=b'F' => Ok(digit - b'A' + 10),
+        _ => Err(MerkleError::Malformed("invalid hex")),
+    }
+}
+
+impl AsRef<[u8]> for Hash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Deref for Hash {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for Hash {
+    fn from(bytes: Vec<u8>) -> Hash {
+        Hash(bytes)
+    }
+}
+
+impl From<&[u8]> for Hash {
+    fn from(bytes: &[u8]) -> Hash {
+        Hash(bytes.to_vec())
+    }
+}
+
+impl From<Hash> for Vec<u8> {
+    fn from(hash: Hash) -> Vec<u8> {
+        hash.0
+    }
+}
+
+/// Hex strings in human-readable formats such as JSON, raw bytes in binary
+/// ones such as bincode or CBOR.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Hash {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Hash {
+    fn deserialize<D>(deserializer: D) -> Result<Hash, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(HashVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(HashVisitor)
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+struct HashVisitor;
+
+#[cfg(feature = "serde")]
+impl serde::de::Visitor<'_> for HashVisitor {
+    type Value = Hash;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a hex string or bytes")
+    }
+
+    fn visit_str<E>(self, hex: &str) -> Result<Hash, E>
+    where
+        E: serde::de::Error,
+    {
+        hex.parse().map_err(E::custom)
+    }
+
+    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Hash, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(Hash::from(bytes))
+    }
+
+    fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<Hash, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(Hash(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Hash;
+    use crate::MerkleError;
+
+    #[test]
+    fn test_hex_round_trip() {
+        let hash: Hash = "00ff10AB".parse().unwrap();
+        assert_eq!(hash.as_bytes(), &[0x00, 0xff, 0x10, 0xab]);
+        assert_eq!(hash.to_string(), "00ff10ab");
+        assert_eq!(format!("{:?}", hash), "Hash(00ff10ab)");
+        assert_eq!("".parse::<Hash>().unwrap(), Hash::default());
+
+        for invalid in ["0", "0g", "00 1", "00 11\n22", "0011\r\n", "\t0011", "+0"].iter() {
+            assert_eq!(
+                invalid.parse::<Hash>().unwrap_err(),
+                MerkleError::Malformed("invalid hex")
+            );
+        }
+    }
+
+    #[test]
+    fn test_base64_round_trip() {
+        let hash = Hash::from(b"merkle".to_vec());
+        assert_eq!(hash.to_base64(), "bWVya2xl");
+        assert_eq!(Hash::from_base64("bWVya2xl").unwrap(), hash);
+        assert_eq!(Hash::from_base64("AAE=").unwrap().as_bytes(), &[0, 1]);
+        assert!(Hash::from_base64("AAE").is_err());
+        assert!(Hash::from_base64("!!!!").is_err());
+    }
+
+    #[test]
+    fn test_equality_and_ordering() {
+        let a = Hash::from(vec![1, 2, 3]);
+        let b = Hash::from(vec![1, 2, 4]);
+        assert_eq!(a, a.clone());
+        assert_ne!(a, b);
+        assert_ne!(a, Hash::from(vec![1, 2]));
+        assert!(a < b);
+        assert!(Hash::from(vec![1, 2]) < a);
+
+        let mut hashes = vec![b.clone(), a.clone()];
+        hashes.sort();
+        assert_eq!(hashes, [a, b]);
+    }
+}
@@ -0,0 +1,153 @@
+// Hash functions and the hashing of leaves and nodes
+use digest::{
+    Digest, FixedOutput, FixedOutputReset, HashMarker, Output, OutputSizeUser, Reset, Update,
+};
+use sha2::Sha256;
+use std::fmt;
+
+const LEAF_SIG: u8 = 0u8;
+const INTERNAL_SIG: u8 = 1u8;
+
+pub(crate) fn hash_leaf<T, H>(value: &T, hasher: &mut H) -> Output<H>
+where
+    T: AsBytes,
+    H: Digest + FixedOutputReset,
+{
+    reset(hasher);
+    Digest::update(hasher, [LEAF_SIG]);
+    Digest::update(hasher, value.as_bytes());
+    finalize(hasher)
+}
+
+/// Leaf of a `SparseMerkleTree`, which commits to its key along with its
+/// value.
+pub(crate) fn hash_keyed_leaf<H>(key: &[u8], value: &[u8], hasher: &mut H) -> Output<H>
+where
+    H: Digest + FixedOutputReset,
+{
+    reset(hasher);
+    Digest::update(hasher, [LEAF_SIG]);
+    Digest::update(hasher, key);
+    Digest::update(hasher, value);
+    finalize(hasher)
+}
+
+pub(crate) fn hash_empty<H>(hasher: &mut H) -> Output<H>
+where
+    H: Digest + FixedOutputReset,
+{
+    reset(hasher);
+    finalize(hasher)
+}
+
+pub(crate) fn hash_concat<H>(parts: &[&[u8]], hasher: &mut H) -> Output<H>
+where
+    H: Digest + FixedOutputReset,
+{
+    reset(hasher);
+    for part in parts {
+        Digest::update(hasher, part);
+    }
+    finalize(hasher)
+}
+
+pub(crate) fn hash_internal_node<H>(left: &[u8], right: Option<&[u8]>, hasher: &mut H) -> Output<H>
+where
+    H: Digest + FixedOutputReset,
+{
+    reset(hasher);
+    Digest::update(hasher, [INTERNAL_SIG]);
+    Digest::update(hasher, left);
+    Digest::update(hasher, right.unwrap_or(left));
+    finalize(hasher)
+}
+
+/// Drops any input left in `hasher` by its caller, so that every hash
+/// starts afresh. The hasher is reset in place rather than replaced, so it
+/// keeps whatever it was set up with, such as a key or a personalization.
+fn reset<H>(hasher: &mut H)
+where
+    H: Digest + FixedOutputReset,
+{
+    Digest::reset(hasher);
+}
+
+/// Finishes the hash fed into `hasher` and leaves it ready for the next one.
+pub(crate) fn finalize<H>(hasher: &mut H) -> Output<H>
+where
+    H: Digest + FixedOutputReset,
+{
+    hasher.finalize_reset()
+}
+
+/// SHA-256, the hash function trees use unless told otherwise.
+///
+/// It is `Clone` but no longer `Copy`: the RustCrypto `Sha256` it wraps
+/// buffers its input and is not `Copy` itself. Code that copied a hasher
+/// should clone it, as the parallel build does for every worker.
+#[derive(Clone, Default)]
+pub struct DefaultHasher(Sha256);
+
+impl DefaultHasher {
+    pub fn new() -> DefaultHasher {
+        DefaultHasher(Sha256::default())
+    }
+}
+
+impl HashMarker for DefaultHasher {}
+
+impl OutputSizeUser for DefaultHasher {
+    type OutputSize = <Sha256 as OutputSizeUser>::OutputSize;
+}
+
+impl Update for DefaultHasher {
+    fn update(&mut self, data: &[u8]) {
+        Update::update(&mut self.0, data)
+    }
+}
+
+impl FixedOutput for DefaultHasher {
+    fn finalize_into(self, out: &mut Output<Self>) {
+        FixedOutput::finalize_into(self.0, out)
+    }
+}
+
+impl Reset for DefaultHasher {
+    fn reset(&mut self) {
+        Reset::reset(&mut self.0)
+    }
+}
+
+impl FixedOutputReset for DefaultHasher {
+    fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+        FixedOutputReset::finalize_into_reset(&mut self.0, out)
+    }
+}
+
+impl fmt::Debug for DefaultHasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DefaultHasher {{ Sha256 }}")
+    }
+}
+
+pub trait AsBytes {
+    fn as_bytes(&self) -> &[u8];
+}
+
+impl AsBytes for &str {
+    fn as_bytes(&self) -> &[u8] {
+        str::as_bytes(self)
+    }
+}
+
+impl AsBytes for String {
+    fn as_bytes(&self) -> &[u8] {
+        String::as_bytes(self)
+    }
+}
+
+impl AsBytes for &[u8] {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
@@ -0,0 +1,30 @@
+// Merkle Tree implementation
+pub mod bitcoin;
+pub mod blake3;
+mod builder;
+mod diff;
+mod encoding;
+mod error;
+mod hash;
+mod hasher;
+mod mmr;
+mod proof;
+#[cfg(feature = "serde")]
+mod serialization;
+mod sparse;
+mod store;
+pub mod sync;
+mod tree;
+
+pub use builder::MerkleTreeBuilder;
+pub use diff::TreeDiff;
+pub use digest::{Digest, FixedOutputReset, Output};
+pub use encoding::{HashAlgorithm, FORMAT_VERSION};
+pub use error::MerkleError;
+pub use hash::Hash;
+pub use hasher::{AsBytes, DefaultHasher};
+pub use mmr::{AncestryProof, Mmr, MmrProof};
+pub use proof::{ConsistencyProof, InclusionProof, MultiProof};
+pub use sparse::{Key, SparseMerkleTree, SparseProof, KEY_BITS};
+pub use store::{FileStore, MemoryStore, NodeStore};
+pub use tree::{Chaining, MerkleTree, OddNodePolicy, TreeMode};
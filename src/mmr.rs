@@ -0,0 +1,665 @@
+// Merkle Mountain Range
+use digest::{Digest, FixedOutputReset, Output};
+
+use super::hash::ct_eq;
+use super::hasher::{hash_empty, hash_internal_node, hash_leaf};
+use super::tree::check_leaf_hash_length;
+use super::{AsBytes, DefaultHasher, Hash, MerkleError};
+
+/// Append-only accumulator made of perfect binary trees, the mountains, one
+/// for every bit set in the number of leaves, from the highest on the left to
+/// the lowest on the right.
+///
+/// Appending a leaf only adds nodes: it merges the mountains it completes but
+/// never rehashes an existing node, so every node of the range of a past size
+/// is still there, and the range takes `2n - popcount(n)` nodes for `n`
+/// leaves. Leaves and nodes are hashed like those of a domain separated
+/// `MerkleTree`, so a range of `2^k` leaves has the root of the tree built
+/// from them.
+///
+/// The peaks of the mountains are bagged into a single root from right to
+/// left: the two rightmost peaks are hashed together, then the next peak with
+/// that hash, and so on. The root of an empty range is the hash of nothing.
+#[derive(Debug, Clone)]
+pub struct Mmr<H = DefaultHasher>
+where
+    H: Digest + FixedOutputReset,
+{
+    hasher: H,
+    /// Nodes in post-order, i.e. in the order they were appended.
+    nodes: Vec<Output<H>>,
+    count_leaves: usize,
+}
+
+impl<H> Mmr<H>
+where
+    H: Digest + FixedOutputReset,
+{
+    pub fn new() -> Mmr<H>
+    where
+        H: Default,
+    {
+        Mmr::with_hasher(Default::default())
+    }
+
+    pub fn with_hasher(hasher: H) -> Mmr<H> {
+        Mmr {
+            hasher,
+            nodes: Vec::new(),
+            count_leaves: 0,
+        }
+    }
+
+    pub fn count_leaves(&self) -> usize {
+        self.count_leaves
+    }
+
+    pub fn count_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count_leaves == 0
+    }
+
+    /// Appends a leaf, followed by the parents of the mountains it completes.
+    pub fn append<T>(&mut self, value: &T)
+    where
+        T: AsBytes,
+    {
+        let leaf = hash_leaf(value, &mut self.hasher);
+        self.append_leaf_hash(&leaf);
+    }
+
+    /// Same as `append`, but takes the already hashed leaf.
+    pub fn append_leaf_hash(&mut self, leaf: &[u8]) {
+        self.try_append_leaf_hash(leaf)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_append_leaf_hash(&mut self, leaf: &[u8]) -> Result<(), MerkleError> {
+        check_leaf_hash_length::<H>(self.count_leaves, leaf)?;
+        let mut node = Output::<H>::clone_from_slice(leaf);
+        let index = self.count_leaves;
+        self.nodes.push(node.clone());
+        for height in 0..index.trailing_ones() as usize {
+            let left = self.node(height, (index >> height) - 1).clone();
+            node = hash_internal_node(&left, Some(&node), &mut self.hasher);
+            self.nodes.push(node.clone());
+        }
+        self.count_leaves += 1;
+        Ok(())
+    }
+
+    /// Peaks of the mountains, from left to right.
+    pub fn peaks(&self) -> Vec<&[u8]> {
+        mountains(self.count_leaves)
+            .into_iter()
+            .map(|(height, index)| self.node(height, index).as_slice())
+            .collect()
+    }
+
+    pub fn root_hash(&self) -> Output<H> {
+        self.root_hash_at(self.count_leaves)
+    }
+
+    /// Root of the range when it had `size` leaves.
+    pub fn root_hash_at(&self, size: usize) -> Output<H> {
+        self.try_root_hash_at(size)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_root_hash_at(&self, size: usize) -> Result<Output<H>, MerkleError> {
+        self.check_size(size)?;
+        let peaks: Vec<Output<H>> = mountains(size)
+            .into_iter()
+            .map(|(height, index)| self.node(height, index).clone())
+            .collect();
+        Ok(bag_peaks(&peaks, &mut H::new()))
+    }
+
+    /// Returns a proof that the leaf at `position` belongs to the range with
+    /// its current number of leaves.
+    pub fn prove(&self, position: usize) -> MmrProof {
+        self.prove_at(position, self.count_leaves)
+    }
+
+    /// Returns a proof that the leaf at `position` belongs to the range as it
+    /// was when it had `size` leaves: the audit path of the leaf within its
+    /// mountain, and the peaks of the other mountains.
+    pub fn prove_at(&self, position: usize, size: usize) -> MmrProof {
+        self.try_prove_at(position, size)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_prove_at(&self, position: usize, size: usize) -> Result<MmrProof, MerkleError> {
+        self.check_size(size)?;
+        if position >= size {
+            return Err(MerkleError::PositionOutOfRange {
+                position,
+                count_leaves: size,
+            });
+        }
+
+        let mut path = Vec::new();
+        let mut peaks = Vec::new();
+        for (height, index) in mountains(size) {
+            if position >> height == index {
+                path = (0..height)
+                    .map(|level| self.node(level, (position >> level) ^ 1).as_slice().into())
+                    .collect();
+            } else {
+                peaks.push(self.node(height, index).as_slice().into());
+            }
+        }
+        Ok(MmrProof {
+            count_leaves: size,
+            path,
+            peaks,
+        })
+    }
+
+    /// Returns a proof that the range with `new_size` leaves was obtained by
+    /// appending to the range with `old_size` leaves.
+    ///
+    /// The proof holds the old peaks, which bag into the old root. Every old
+    /// peak is a node of the new range, so the new peaks follow from them
+    /// with the right siblings met while climbing from the rightmost old peak
+    /// of each new mountain, and the peaks of the mountains made only of new
+    /// leaves.
+    pub fn ancestry_proof(&self, old_size: usize, new_size: usize) -> AncestryProof {
+        self.try_ancestry_proof(old_size, new_size)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_ancestry_proof(
+        &self,
+        old_size: usize,
+        new_size: usize,
+    ) -> Result<AncestryProof, MerkleError> {
+        self.check_size(new_size)?;
+        if old_size > new_size {
+            return Err(MerkleError::OldSizeOutOfRange {
+                old_size,
+                count_leaves: new_size,
+            });
+        }
+
+        let old_mountains = mountains(old_size);
+        let old_peaks = old_mountains
+            .iter()
+            .map(|&(height, index)| self.node(height, index).as_slice().into())
+            .collect();
+
+        let mut hashes = Vec::new();
+        for (new_height, new_index) in mountains(new_size) {
+            let start = new_index << new_height;
+            if start >= old_size {
+                hashes.push(self.node(new_height, new_index).as_slice().into());
+                continue;
+            }
+
+            let end = start + (1 << new_height);
+            let (mut height, mut index) = *old_mountains
+                .iter()
+                .rfind(|&&(height, index)| index << height < end)
+                .unwrap();
+            while height < new_height {
+                if index & 1 == 0 {
+                    hashes.push(self.node(height, index + 1).as_slice().into());
+                }
+                height += 1;
+                index >>= 1;
+            }
+        }
+        Ok(AncestryProof {
+            old_size,
+            new_size,
+            old_peaks,
+            hashes,
+        })
+    }
+
+    /// Node at `height` above the leaves with `index` among the nodes of
+    /// that height. The nodes of a perfect subtree follow the nodes of the
+    /// range of the leaves before it.
+    fn node(&self, height: usize, index: usize) -> &Output<H> {
+        let first_leaf = index << height;
+        &self.nodes[count_nodes(first_leaf) + (2 << height) - 2]
+    }
+
+    fn check_size(&self, size: usize) -> Result<(), MerkleError> {
+        if size > self.count_leaves {
+            return Err(MerkleError::OldSizeOutOfRange {
+                old_size: size,
+                count_leaves: self.count_leaves,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<H> Default for Mmr<H>
+where
+    H: Digest + FixedOutputReset + Default,
+{
+    fn default() -> Mmr<H> {
+        Mmr::new()
+    }
+}
+
+/// Proof that a leaf belongs to a `Mmr` of a given size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MmrProof {
+    count_leaves: usize,
+    path: Vec<Hash>,
+    peaks: Vec<Hash>,
+}
+
+impl MmrProof {
+    /// Assembles a proof received from elsewhere.
+    pub fn new(count_leaves: usize, path: Vec<Hash>, peaks: Vec<Hash>) -> MmrProof {
+        MmrProof {
+            count_leaves,
+            path,
+            peaks,
+        }
+    }
+
+    /// Number of leaves of the range the proof was generated against.
+    pub fn count_leaves(&self) -> usize {
+        self.count_leaves
+    }
+
+    /// Sibling hashes from the leaf up to the peak of its mountain.
+    pub fn path(&self) -> &[Hash] {
+        &self.path
+    }
+
+    /// Peaks of the other mountains, from left to right.
+    pub fn peaks(&self) -> &[Hash] {
+        &self.peaks
+    }
+
+    /// Checks that `value` is the leaf at `position` of the range with `root`
+    /// and `count_leaves` leaves. The bagged peaks do not commit to the size
+    /// of the range, so it comes from the verifier and a proof generated for
+    /// another size is rejected.
+    pub fn verify<T, H>(
+        &self,
+        root: &[u8],
+        count_leaves: usize,
+        position: usize,
+        value: &T,
+        hasher: &mut H,
+    ) -> bool
+    where
+        T: AsBytes,
+        H: Digest + FixedOutputReset,
+    {
+        let leaf = hash_leaf(value, hasher);
+        self.verify_leaf_hash(root, count_leaves, position, &leaf, hasher)
+    }
+
+    /// Same as `verify`, but takes the already hashed leaf.
+    pub fn verify_leaf_hash<H>(
+        &self,
+        root: &[u8],
+        count_leaves: usize,
+        position: usize,
+        leaf: &[u8],
+        hasher: &mut H,
+    ) -> bool
+    where
+        H: Digest + FixedOutputReset,
+    {
+        let mountains = mountains(count_leaves);
+        let len = <H as Digest>::output_size();
+        if count_leaves != self.count_leaves
+            || position >= count_leaves
+            || self.peaks.len() + 1 != mountains.len()
+            || leaf.len() != len
+            || self.path.iter().chain(&self.peaks).any(|h| h.len() != len)
+        {
+            return false;
+        }
+
+        let mut others = self.peaks.iter();
+        let mut peaks = Vec::with_capacity(mountains.len());
+        for (height, index) in mountains {
+            if position >> height != index {
+                peaks.push(Output::<H>::clone_from_slice(others.next().unwrap()));
+                continue;
+            }
+            if self.path.len() != height {
+                return false;
+            }
+            let mut node = Output::<H>::clone_from_slice(leaf);
+            for (level, sibling) in self.path.iter().enumerate() {
+                node = if (position >> level) & 1 == 1 {
+                    hash_internal_node(sibling, Some(&node), hasher)
+                } else {
+                    hash_internal_node(&node, Some(sibling), hasher)
+                };
+            }
+            peaks.push(node);
+        }
+        ct_eq(&bag_peaks(&peaks, hasher), root)
+    }
+}
+
+/// Proof that a `Mmr` with `new_size` leaves extends the one with `old_size`
+/// leaves, see `Mmr::ancestry_proof`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AncestryProof {
+    old_size: usize,
+    new_size: usize,
+    old_peaks: Vec<Hash>,
+    hashes: Vec<Hash>,
+}
+
+impl AncestryProof {
+    /// Assembles a proof received from elsewhere.
+    pub fn new(
+        old_size: usize,
+        new_size: usize,
+        old_peaks: Vec<Hash>,
+        hashes: Vec<Hash>,
+    ) -> AncestryProof {
+        AncestryProof {
+            old_size,
+            new_size,
+            old_peaks,
+            hashes,
+        }
+    }
+
+    pub fn old_size(&self) -> usize {
+        self.old_size
+    }
+
+    pub fn new_size(&self) -> usize {
+        self.new_size
+    }
+
+    /// Peaks of the old range, from left to right.
+    pub fn old_peaks(&self) -> &[Hash] {
+        &self.old_peaks
+    }
+
+    /// Siblings and new peaks needed to compute the new peaks, in the order
+    /// they are met going through the new mountains from left to right.
+    pub fn hashes(&self) -> &[Hash] {
+        &self.hashes
+    }
+
+    /// Checks that the range with `new_root` and `new_size` leaves was
+    /// obtained by appending to the range with `old_root` and `old_size`
+    /// leaves. As with `MmrProof::verify`, the sizes come from the verifier.
+    pub fn verify<H>(
+        &self,
+        old_root: &[u8],
+        old_size: usize,
+        new_root: &[u8],
+        new_size: usize,
+        hasher: &mut H,
+    ) -> bool
+    where
+        H: Digest + FixedOutputReset,
+    {
+        let old_mountains = mountains(old_size);
+        let len = <H as Digest>::output_size();
+        if old_size != self.old_size
+            || new_size != self.new_size
+            || old_size > new_size
+            || self.old_peaks.len() != old_mountains.len()
+            || self
+                .old_peaks
+                .iter()
+                .chain(&self.hashes)
+                .any(|h| h.len() != len)
+        {
+            return false;
+        }
+
+        let old_peaks: Vec<Output<H>> = self
+            .old_peaks
+            .iter()
+            .map(|peak| Output::<H>::clone_from_slice(peak))
+            .collect();
+        if !ct_eq(&bag_peaks(&old_peaks, hasher), old_root) {
+            return false;
+        }
+
+        let mut old = old_mountains.into_iter().zip(old_peaks).peekable();
+        let mut hashes = self.hashes.iter();
+        let mut new_peaks = Vec::new();
+        for (new_height, new_index) in mountains(new_size) {
+            let start = new_index << new_height;
+            if start >= old_size {
+                match hashes.next() {
+                    Some(peak) => new_peaks.push(Output::<H>::clone_from_slice(peak)),
+                    None => return false,
+                }
+                continue;
+            }
+
+            let end = start + (1 << new_height);
+            let mut inside = Vec::new();
+            while let Some(peak) = old.next_if(|&((height, index), _)| index << height < end) {
+                inside.push(peak);
+            }
+            let ((mut height, mut index), mut node) = inside.pop().unwrap();
+            while height < new_height {
+                node = if index & 1 == 1 {
+                    match inside.pop() {
+                        Some((_, left)) => hash_internal_node(&left, Some(&node), hasher),
+                        None => return false,
+                    }
+                } else {
+                    match hashes.next() {
+                        Some(right) => hash_internal_node(&node, Some(right), hasher),
+                        None => return false,
+                    }
+                };
+                height += 1;
+                index >>= 1;
+            }
+            if !inside.is_empty() {
+                return false;
+            }
+            new_peaks.push(node);
+        }
+        hashes.next().is_none() && ct_eq(&bag_peaks(&new_peaks, hasher), new_root)
+    }
+}
+
+/// Heights and indices of the peaks of a range of `count_leaves` leaves, from
+/// left to right.
+fn mountains(count_leaves: usize) -> Vec<(usize, usize)> {
+    let mut mountains = Vec::new();
+    let mut start = 0;
+    for height in (0..usize::BITS as usize).rev() {
+        if count_leaves >> height & 1 == 1 {
+            mountains.push((height, start >> height));
+            start += 1 << height;
+        }
+    }
+    mountains
+}
+
+/// Number of nodes of a range of `count_leaves` leaves.
+fn count_nodes(count_leaves: usize) -> usize {
+    2 * count_leaves - count_leaves.count_ones() as usize
+}
+
+fn bag_peaks<H>(peaks: &[Output<H>], hasher: &mut H) -> Output<H>
+where
+    H: Digest + FixedOutputReset,
+{
+    match peaks.split_last() {
+        None => hash_empty(hasher),
+        Some((last, rest)) => rest.iter().rev().fold(last.clone(), |right, left| {
+            hash_internal_node(left, Some(&right), hasher)
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{DefaultHasher, Hash, MerkleError, MerkleTree};
+    use super::{count_nodes, Mmr, MmrProof};
+
+    fn build(count: usize) -> Mmr {
+        let mut mmr = Mmr::new();
+        for i in 0..count {
+            mmr.append(&i.to_string());
+        }
+        mmr
+    }
+
+    #[test]
+    fn test_appending_only_adds_nodes() {
+        let mut mmr = Mmr::<DefaultHasher>::new();
+        let mut previous: Vec<Vec<u8>> = Vec::new();
+        for i in 0..40 {
+            mmr.append(&i.to_string());
+            assert_eq!(mmr.count_nodes(), count_nodes(i + 1));
+            assert_eq!(mmr.peaks().len(), (i + 1).count_ones() as usize);
+            assert!(mmr
+                .nodes
+                .iter()
+                .zip(&previous)
+                .all(|(a, b)| a.as_slice() == b));
+            previous = mmr.nodes.iter().map(|n| n.to_vec()).collect();
+        }
+        assert_eq!(mmr.count_nodes(), 2 * 40 - 2);
+    }
+
+    #[test]
+    fn test_single_mountain_matches_the_merkle_tree() {
+        for &count in [1, 2, 4, 8, 16, 32].iter() {
+            let values: Vec<String> = (0..count).map(|i: usize| i.to_string()).collect();
+            let t: MerkleTree = MerkleTree::build(&values);
+            assert_eq!(build(count).root_hash().as_slice(), t.root_hash());
+        }
+        let empty = Mmr::<DefaultHasher>::new();
+        let t: MerkleTree = MerkleTree::build::<&str>(&[]);
+        assert_eq!(empty.root_hash().as_slice(), t.root_hash());
+    }
+
+    #[test]
+    fn test_historical_roots() {
+        let mmr = build(30);
+        for size in 0..31 {
+            assert_eq!(mmr.root_hash_at(size), build(size).root_hash());
+        }
+        assert!(mmr.try_root_hash_at(31).is_err());
+    }
+
+    #[test]
+    fn test_inclusion_proofs_against_every_size() {
+        let mmr = build(25);
+        let mut hasher = DefaultHasher::new();
+        for size in 1..26 {
+            let root = mmr.root_hash_at(size);
+            for position in 0..size {
+                let value = position.to_string();
+                let proof = mmr.prove_at(position, size);
+                assert!(proof.verify(&root, size, position, &value, &mut hasher));
+                assert!(!proof.verify(&root, size, position, &"x", &mut hasher));
+                assert!(!proof.verify(&root, size, position ^ 1, &value, &mut hasher));
+                assert!(!proof.verify(&root, size + 1, position, &value, &mut hasher));
+            }
+            assert!(mmr.try_prove_at(size, size).is_err());
+        }
+        assert!(mmr.try_prove_at(0, 26).is_err());
+    }
+
+    #[test]
+    fn test_ancestry_proofs_between_every_sizes() {
+        let mmr = build(20);
+        let mut hasher = DefaultHasher::new();
+        for new_size in 0..21 {
+            let new_root = mmr.root_hash_at(new_size);
+            for old_size in 0..new_size + 1 {
+                let old_root = mmr.root_hash_at(old_size);
+                let proof = mmr.ancestry_proof(old_size, new_size);
+                assert!(proof.verify(&old_root, old_size, &new_root, new_size, &mut hasher));
+                if old_size != new_size {
+                    assert!(!proof.verify(&new_root, old_size, &new_root, new_size, &mut hasher));
+                }
+            }
+        }
+
+        let other = build(7);
+        let mut hasher = DefaultHasher::new();
+        let mut forked = build(5);
+        forked.append(&"x");
+        forked.append(&"y");
+        let proof = other.ancestry_proof(6, 7);
+        let (old_root, new_root) = (forked.root_hash_at(6), forked.root_hash());
+        assert!(!proof.verify(&old_root, 6, &new_root, 7, &mut hasher));
+        assert!(mmr.try_ancestry_proof(5, 4).is_err());
+    }
+
+    #[test]
+    fn test_tampered_proofs_are_rejected() {
+        let mmr = build(11);
+        let root = mmr.root_hash();
+        let mut hasher = DefaultHasher::new();
+        let proof = mmr.prove(4);
+
+        let mut path = proof.path().to_vec();
+        path[0] = Hash::from(vec![0; 32]);
+        let tampered = MmrProof::new(11, path, proof.peaks().to_vec());
+        assert!(!tampered.verify(&root, 11, 4, &"4", &mut hasher));
+
+        let tampered = MmrProof::new(11, proof.path().to_vec(), Vec::new());
+        assert!(!tampered.verify(&root, 11, 4, &"4", &mut hasher));
+
+        let tampered = MmrProof::new(12, proof.path().to_vec(), proof.peaks().to_vec());
+        assert!(!tampered.verify(&root, 11, 4, &"4", &mut hasher));
+        assert!(!tampered.verify(&root, 12, 4, &"4", &mut hasher));
+    }
+
+    #[test]
+    fn test_proofs_cannot_choose_the_size_of_the_range() {
+        let mmr = build(3);
+        let root = mmr.root_hash();
+        let mut hasher = DefaultHasher::new();
+        // The last of 5 leaves is a mountain of its own right of a peak over
+        // four leaves, just like the last of 3 leaves is right of a peak over
+        // two, so the same peaks put leaf 2 at position 4.
+        let proof = mmr.prove(2);
+        let moved = MmrProof::new(5, Vec::new(), proof.peaks().to_vec());
+        assert!(moved.verify(&root, 5, 4, &"2", &mut hasher));
+        assert!(!moved.verify(&root, 3, 4, &"2", &mut hasher));
+        assert!(proof.verify(&root, 3, 2, &"2", &mut hasher));
+
+        let ancestry = mmr.ancestry_proof(2, 3);
+        let (old_root, new_root) = (mmr.root_hash_at(2), mmr.root_hash());
+        assert!(ancestry.verify(&old_root, 2, &new_root, 3, &mut hasher));
+        assert!(!ancestry.verify(&old_root, 2, &new_root, 4, &mut hasher));
+    }
+
+    #[test]
+    fn test_leaf_hashes_of_the_wrong_length_are_rejected() {
+        let mut mmr = build(3);
+        assert_eq!(
+            mmr.try_append_leaf_hash(&[0; 31]).unwrap_err(),
+            MerkleError::LeafHashLengthMismatch {
+                position: 3,
+                expected: 32,
+                received: 31
+            }
+        );
+        assert_eq!(mmr.count_nodes(), count_nodes(3));
+
+        let leaf = build(4).nodes[4];
+        mmr.try_append_leaf_hash(&leaf).unwrap();
+        assert_eq!(mmr.root_hash(), build(4).root_hash());
+    }
+}
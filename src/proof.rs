@@ -0,0 +1,830 @@
+// Merkle proofs
+use digest::{Digest, FixedOutputReset, Output};
+
+use super::hash::ct_eq;
+use super::hasher::hash_empty;
+use super::tree::level_widths;
+use super::{AsBytes, Hash, MerkleError, TreeMode};
+
+/// Audit path proving that a value is stored at a given leaf position of a
+/// `MerkleTree` with a known root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InclusionProof {
+    mode: TreeMode,
+    count_leaves: usize,
+    path: Vec<Hash>,
+}
+
+impl InclusionProof {
+    /// Assembles a proof received from elsewhere. An RFC 6962 audit path, as
+    /// returned by a Certificate Transparency log's `get-proof-by-hash`, is
+    /// `InclusionProof::new(TreeMode::RFC6962, tree_size, audit_path)`.
+    pub fn new(mode: TreeMode, count_leaves: usize, path: Vec<Hash>) -> InclusionProof {
+        InclusionProof {
+            mode,
+            count_leaves,
+            path,
+        }
+    }
+
+    /// Mode of the tree the proof was generated from.
+    pub fn mode(&self) -> TreeMode {
+        self.mode
+    }
+
+    /// Number of leaves in the tree the proof was generated from.
+    pub fn count_leaves(&self) -> usize {
+        self.count_leaves
+    }
+
+    /// Sibling hashes ordered from the leaf level up to the root.
+    pub fn path(&self) -> &[Hash] {
+        &self.path
+    }
+
+    /// Checks that `value` is the leaf at `position` of the tree with `root`
+    /// and `count_leaves` leaves, hashed in `mode`. The size and the mode come
+    /// from the verifier, never from the proof: a proof generated for another
+    /// size or in another mode is rejected.
+    pub fn verify<T, H>(
+        &self,
+        root: &[u8],
+        count_leaves: usize,
+        position: usize,
+        value: &T,
+        mode: TreeMode,
+        hasher: &mut H,
+    ) -> bool
+    where
+        T: AsBytes,
+        H: Digest + FixedOutputReset,
+    {
+        self.try_verify(root, count_leaves, position, value, mode, hasher)
+            .unwrap_or(false)
+    }
+
+    /// Same as `verify`, but reports the values and hashes `mode` cannot hash
+    /// instead of rejecting them.
+    pub fn try_verify<T, H>(
+        &self,
+        root: &[u8],
+        count_leaves: usize,
+        position: usize,
+        value: &T,
+        mode: TreeMode,
+        hasher: &mut H,
+    ) -> Result<bool, MerkleError>
+    where
+        T: AsBytes,
+        H: Digest + FixedOutputReset,
+    {
+        let leaf = mode.hash_leaf(position, value, hasher)?;
+        self.try_verify_leaf_hash(root, count_leaves, position, &leaf, mode, hasher)
+    }
+
+    /// Same as `verify`, but takes the already hashed leaf.
+    pub fn verify_leaf_hash<H>(
+        &self,
+        root: &[u8],
+        count_leaves: usize,
+        position: usize,
+        leaf: &[u8],
+        mode: TreeMode,
+        hasher: &mut H,
+    ) -> bool
+    where
+        H: Digest + FixedOutputReset,
+    {
+        self.try_verify_leaf_hash(root, count_leaves, position, leaf, mode, hasher)
+            .unwrap_or(false)
+    }
+
+    pub fn try_verify_leaf_hash<H>(
+        &self,
+        root: &[u8],
+        count_leaves: usize,
+        position: usize,
+        leaf: &[u8],
+        mode: TreeMode,
+        hasher: &mut H,
+    ) -> Result<bool, MerkleError>
+    where
+        H: Digest + FixedOutputReset,
+    {
+        if mode != self.mode
+            || count_leaves != self.count_leaves
+            || position >= count_leaves
+            || !has_output_size::<H>(leaf, &self.path)
+        {
+            return Ok(false);
+        }
+
+        let widths = level_widths(count_leaves);
+        let mut siblings = self.path.iter();
+        let mut index = position;
+        let mut node = Output::<H>::clone_from_slice(leaf);
+        for &width in &widths[..widths.len() - 1] {
+            let sibling = index ^ 1;
+            node = if sibling >= width {
+                mode.hash_lone_node(&node, hasher)?
+            } else {
+                match siblings.next() {
+                    Some(s) if index & 1 == 0 => mode.hash_node(&node, s, hasher)?,
+                    Some(s) => mode.hash_node(s, &node, hasher)?,
+                    None => return Ok(false),
+                }
+            };
+            index /= 2;
+        }
+
+        Ok(siblings.next().is_none() && ct_eq(&node, root))
+    }
+}
+
+/// Checks that `hash` and all `hashes` are as long as the output of `H`.
+fn has_output_size<H>(hash: &[u8], hashes: &[Hash]) -> bool
+where
+    H: Digest + FixedOutputReset,
+{
+    let len = <H as Digest>::output_size();
+    hash.len() == len && hashes.iter().all(|h| h.len() == len)
+}
+
+/// Proof that several values are stored at given leaf positions of a
+/// `MerkleTree`, carrying every needed sibling hash only once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MultiProof {
+    mode: TreeMode,
+    count_leaves: usize,
+    hashes: Vec<Hash>,
+}
+
+impl MultiProof {
+    /// Assembles a proof received from elsewhere.
+    pub fn new(mode: TreeMode, count_leaves: usize, hashes: Vec<Hash>) -> MultiProof {
+        MultiProof {
+            mode,
+            count_leaves,
+            hashes,
+        }
+    }
+
+    /// Mode of the tree the proof was generated from.
+    pub fn mode(&self) -> TreeMode {
+        self.mode
+    }
+
+    /// Number of leaves in the tree the proof was generated from.
+    pub fn count_leaves(&self) -> usize {
+        self.count_leaves
+    }
+
+    /// Sibling hashes ordered by level, from the leaves up, and by position
+    /// within a level.
+    pub fn hashes(&self) -> &[Hash] {
+        &self.hashes
+    }
+
+    /// Checks that every `values[i]` is the leaf at `positions[i]` of the tree
+    /// with `root` and `count_leaves` leaves, hashed in `mode`. Positions may
+    /// be given in any order.
+    pub fn verify<T, H>(
+        &self,
+        root: &[u8],
+        count_leaves: usize,
+        positions: &[usize],
+        values: &[T],
+        mode: TreeMode,
+        hasher: &mut H,
+    ) -> bool
+    where
+        T: AsBytes,
+        H: Digest + FixedOutputReset,
+    {
+        self.try_verify(root, count_leaves, positions, values, mode, hasher)
+            .unwrap_or(false)
+    }
+
+    /// Same as `verify`, but reports the values and hashes `mode` cannot hash
+    /// instead of rejecting them.
+    pub fn try_verify<T, H>(
+        &self,
+        root: &[u8],
+        count_leaves: usize,
+        positions: &[usize],
+        values: &[T],
+        mode: TreeMode,
+        hasher: &mut H,
+    ) -> Result<bool, MerkleError>
+    where
+        T: AsBytes,
+        H: Digest + FixedOutputReset,
+    {
+        let leaves = positions
+            .iter()
+            .zip(values)
+            .map(|(&position, v)| mode.hash_leaf(position, v, hasher))
+            .collect::<Result<Vec<Output<H>>, MerkleError>>()?;
+        self.try_verify_leaf_hashes(root, count_leaves, positions, &leaves, mode, hasher)
+    }
+
+    /// Same as `verify`, but takes the already hashed leaves.
+    pub fn verify_leaf_hashes<H, L>(
+        &self,
+        root: &[u8],
+        count_leaves: usize,
+        positions: &[usize],
+        leaves: &[L],
+        mode: TreeMode,
+        hasher: &mut H,
+    ) -> bool
+    where
+        H: Digest + FixedOutputReset,
+        L: AsRef<[u8]>,
+    {
+        self.try_verify_leaf_hashes(root, count_leaves, positions, leaves, mode, hasher)
+            .unwrap_or(false)
+    }
+
+    pub fn try_verify_leaf_hashes<H, L>(
+        &self,
+        root: &[u8],
+        count_leaves: usize,
+        positions: &[usize],
+        leaves: &[L],
+        mode: TreeMode,
+        hasher: &mut H,
+    ) -> Result<bool, MerkleError>
+    where
+        H: Digest + FixedOutputReset,
+        L: AsRef<[u8]>,
+    {
+        if mode != self.mode
+            || count_leaves != self.count_leaves
+            || positions.is_empty()
+            || positions.len() != leaves.len()
+            || positions.iter().any(|&p| p >= count_leaves)
+            || leaves
+                .iter()
+                .any(|leaf| !has_output_size::<H>(leaf.as_ref(), &self.hashes))
+        {
+            return Ok(false);
+        }
+
+        let mut known: Vec<(usize, Output<H>)> = positions
+            .iter()
+            .cloned()
+            .zip(
+                leaves
+                    .iter()
+                    .map(|leaf| Output::<H>::clone_from_slice(leaf.as_ref())),
+            )
+            .collect();
+        known.sort();
+        if known
+            .windows(2)
+            .any(|w| w[0].0 == w[1].0 && w[0].1 != w[1].1)
+        {
+            return Ok(false);
+        }
+        known.dedup();
+
+        let widths = level_widths(count_leaves);
+        let mut siblings = self.hashes.iter();
+        for &width in &widths[..widths.len() - 1] {
+            let mut parents = Vec::with_capacity(known.len());
+            let mut i = 0;
+            while i < known.len() {
+                let (index, ref node) = known[i];
+                let sibling = index ^ 1;
+                let parent = if i + 1 < known.len() && known[i + 1].0 == sibling {
+                    i += 1;
+                    mode.hash_node(node, &known[i].1, hasher)?
+                } else if sibling >= width {
+                    mode.hash_lone_node(node, hasher)?
+                } else {
+                    match siblings.next() {
+                        Some(s) if index & 1 == 0 => mode.hash_node(node, s, hasher)?,
+                        Some(s) => mode.hash_node(s, node, hasher)?,
+                        None => return Ok(false),
+                    }
+                };
+                parents.push((index / 2, parent));
+                i += 1;
+            }
+            known = parents;
+        }
+
+        Ok(siblings.next().is_none() && ct_eq(&known[0].1, root))
+    }
+}
+
+/// Proof that a tree is an append-only extension of an older version of
+/// itself. With `TreeMode::RFC6962` these are the consistency proofs of
+/// RFC 6962, section 2.1.2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConsistencyProof {
+    mode: TreeMode,
+    hashes: Vec<Hash>,
+}
+
+impl ConsistencyProof {
+    /// Assembles a proof received from elsewhere. The `consistency` array of
+    /// a Certificate Transparency log's `get-sth-consistency` response is
+    /// `ConsistencyProof::new(TreeMode::RFC6962, consistency)`.
+    pub fn new(mode: TreeMode, hashes: Vec<Hash>) -> ConsistencyProof {
+        ConsistencyProof { mode, hashes }
+    }
+
+    /// Mode of the tree the proof was generated from.
+    pub fn mode(&self) -> TreeMode {
+        self.mode
+    }
+
+    /// Hashes of the proof: the last full subtree of the old tree, unless it
+    /// is the old root, followed by its audit path in the new tree.
+    pub fn hashes(&self) -> &[Hash] {
+        &self.hashes
+    }
+
+    /// Checks that the tree with `new_root` and `new_size` leaves starts with
+    /// the `old_size` leaves of the tree with `old_root`, both hashed in
+    /// `mode`.
+    pub fn verify<H>(
+        &self,
+        old_root: &[u8],
+        old_size: usize,
+        new_root: &[u8],
+        new_size: usize,
+        mode: TreeMode,
+        hasher: &mut H,
+    ) -> bool
+    where
+        H: Digest + FixedOutputReset,
+    {
+        self.try_verify(old_root, old_size, new_root, new_size, mode, hasher)
+            .unwrap_or(false)
+    }
+
+    /// Same as `verify`, but reports the hashes `mode` cannot hash instead of
+    /// rejecting them.
+    pub fn try_verify<H>(
+        &self,
+        old_root: &[u8],
+        old_size: usize,
+        new_root: &[u8],
+        new_size: usize,
+        mode: TreeMode,
+        hasher: &mut H,
+    ) -> Result<bool, MerkleError>
+    where
+        H: Digest + FixedOutputReset,
+    {
+        if mode != self.mode || old_size > new_size {
+            return Ok(false);
+        }
+        if old_size == 0 {
+            return Ok(self.hashes.is_empty() && ct_eq(old_root, &hash_empty(hasher)));
+        }
+        if old_size == new_size {
+            return Ok(self.hashes.is_empty() && ct_eq(old_root, new_root));
+        }
+
+        if !has_output_size::<H>(old_root, &self.hashes) {
+            return Ok(false);
+        }
+
+        let level = old_size.trailing_zeros() as usize;
+        let mut hashes = self.hashes.iter();
+        let start = if old_size.is_power_of_two() {
+            Output::<H>::clone_from_slice(old_root)
+        } else {
+            match hashes.next() {
+                Some(h) => Output::<H>::clone_from_slice(h),
+                None => return Ok(false),
+            }
+        };
+
+        let old_widths = level_widths(old_size);
+        let new_widths = level_widths(new_size);
+        let mut index = (old_size >> level) - 1;
+        let mut old_node = start.clone();
+        let mut new_node = start;
+        let depth = new_widths.len() - 1;
+        for (level, &width) in new_widths[..depth].iter().enumerate().skip(level) {
+            let old_is_root = level >= old_widths.len() - 1;
+            if index & 1 == 1 {
+                let sibling = match hashes.next() {
+                    Some(s) => s,
+                    None => return Ok(false),
+                };
+                if !old_is_root {
+                    old_node = mode.hash_node(sibling, &old_node, hasher)?;
+                }
+                new_node = mode.hash_node(sibling, &new_node, hasher)?;
+            } else {
+                if !old_is_root {
+                    old_node = mode.hash_lone_node(&old_node, hasher)?;
+                }
+                new_node = if index + 1 < width {
+                    match hashes.next() {
+                        Some(s) => mode.hash_node(&new_node, s, hasher)?,
+                        None => return Ok(false),
+                    }
+                } else {
+                    mode.hash_lone_node(&new_node, hasher)?
+                };
+            }
+            index /= 2;
+        }
+
+        Ok(hashes.next().is_none() && ct_eq(&old_node, old_root) && ct_eq(&new_node, new_root))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{DefaultHasher, Hash, MerkleTree, TreeMode};
+    use super::{ConsistencyProof, InclusionProof, MultiProof};
+
+    const MODES: [TreeMode; 3] = [TreeMode::DEFAULT, TreeMode::RFC6962, TreeMode::BITCOIN];
+
+    fn build(values: &[&str], mode: TreeMode) -> MerkleTree {
+        MerkleTree::build_with_mode(values, DefaultHasher::new(), mode)
+    }
+
+    #[test]
+    fn test_every_leaf_has_a_valid_inclusion_proof() {
+        let values = ["a", "b", "c", "d", "e", "f", "g", "h", "i"];
+        for &mode in MODES.iter() {
+            for count in 1..values.len() + 1 {
+                let t = build(&values[..count], mode);
+                for (position, value) in values[..count].iter().enumerate() {
+                    let proof = t.prove(position);
+                    assert!(proof.verify(
+                        t.root_hash(),
+                        count,
+                        position,
+                        value,
+                        mode,
+                        &mut DefaultHasher::new()
+                    ));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_inclusion_proof_is_bound_to_the_mode() {
+        let values = ["a", "b", "c", "d", "e"];
+        let duplicate = build(&values, TreeMode::DEFAULT);
+        let promote = build(&values, TreeMode::RFC6962);
+        let mut hasher = DefaultHasher::new();
+
+        assert_ne!(duplicate.root_hash(), promote.root_hash());
+        for (proof, tree) in [
+            (duplicate.prove(4), &promote),
+            (promote.prove(4), &duplicate),
+        ] {
+            let root = tree.root_hash();
+            assert!(!proof.verify(root, 5, 4, &"e", tree.mode(), &mut hasher));
+            assert!(!proof.verify(root, 5, 4, &"e", proof.mode(), &mut hasher));
+        }
+    }
+
+    #[test]
+    fn test_proofs_cannot_choose_the_mode_they_are_checked_in() {
+        let t = build(&["a", "b"], TreeMode::DEFAULT);
+        let mut hasher = DefaultHasher::new();
+        // Without domain separation, the internal node of a two leaf tree
+        // is the leaf hash of the bytes it was hashed from.
+        let mut forged = vec![1u8];
+        forged.extend_from_slice(&t.leaves()[0]);
+        forged.extend_from_slice(&t.leaves()[1]);
+        let forged = forged.as_slice();
+
+        let root = t.root_hash();
+        let proof = InclusionProof::new(TreeMode::BITCOIN, 1, Vec::new());
+        assert!(proof.verify(root, 1, 0, &forged, TreeMode::BITCOIN, &mut hasher));
+        assert!(!proof.verify(root, 1, 0, &forged, TreeMode::DEFAULT, &mut hasher));
+        let decoded = InclusionProof::from_bytes(&proof.to_bytes().unwrap()).unwrap();
+        assert!(!decoded.verify(root, 1, 0, &forged, TreeMode::DEFAULT, &mut hasher));
+
+        let proof = MultiProof::new(TreeMode::BITCOIN, 1, Vec::new());
+        assert!(!proof.verify(root, 1, &[0], &[forged], TreeMode::DEFAULT, &mut hasher));
+
+        let proof = t.consistency_proof(1);
+        let old = build(&["a"], TreeMode::DEFAULT);
+        let mode = TreeMode::RFC6962;
+        assert!(!proof.verify(old.root_hash(), 1, t.root_hash(), 2, mode, &mut hasher));
+    }
+
+    #[test]
+    fn test_proofs_cannot_choose_the_size_of_the_tree() {
+        let mode = TreeMode::RFC6962;
+        let t = build(&["a", "b", "c"], mode);
+        let mut hasher = DefaultHasher::new();
+        // The lone last leaf of a tree with 5 leaves climbs the same path as
+        // the one of a tree with 3 leaves, only from position 4.
+        let proof = InclusionProof::new(mode, 5, t.prove(2).path().to_vec());
+        assert!(proof.verify(t.root_hash(), 5, 4, &"c", mode, &mut hasher));
+        assert!(!proof.verify(t.root_hash(), 3, 4, &"c", mode, &mut hasher));
+        assert!(!proof.verify(t.root_hash(), 3, 2, &"c", mode, &mut hasher));
+        assert!(t
+            .prove(2)
+            .verify(t.root_hash(), 3, 2, &"c", mode, &mut hasher));
+
+        let proof = MultiProof::new(mode, 5, t.prove_many(&[2]).hashes().to_vec());
+        assert!(!proof.verify(t.root_hash(), 3, &[4], &["c"], mode, &mut hasher));
+    }
+
+    #[test]
+    fn test_inclusion_proof_rejects_wrong_value_position_and_root() {
+        let values = ["a", "b", "c", "d", "e"];
+        let t: MerkleTree = MerkleTree::build(&values);
+        let other: MerkleTree = MerkleTree::build(&["a", "b"]);
+        let proof = t.prove(2);
+        let mut hasher = DefaultHasher::new();
+
+        let mode = TreeMode::DEFAULT;
+        assert!(proof.verify(t.root_hash(), 5, 2, &"c", mode, &mut hasher));
+        assert!(!proof.verify(t.root_hash(), 5, 2, &"x", mode, &mut hasher));
+        assert!(!proof.verify(t.root_hash(), 5, 3, &"c", mode, &mut hasher));
+        assert!(!proof.verify(t.root_hash(), 5, 5, &"c", mode, &mut hasher));
+        assert!(!proof.verify(other.root_hash(), 5, 2, &"c", mode, &mut hasher));
+    }
+
+    #[test]
+    fn test_inclusion_proof_of_the_lone_last_leaf_skips_the_duplicate() {
+        let t: MerkleTree = MerkleTree::build(&["a", "b", "c", "d", "e"]);
+        let proof = t.prove(4);
+
+        assert_eq!(proof.path().len(), 1);
+        assert_eq!(proof.path()[0], Hash::from(t.node(2, 0).to_vec()));
+    }
+
+    #[test]
+    fn test_proofs_reject_hashes_of_the_wrong_length() {
+        let t = build(&["a", "b", "c", "d", "e"], TreeMode::DEFAULT);
+        let mut hasher = DefaultHasher::new();
+        let leaf = t.leaves()[2].to_vec();
+        let short = leaf[..31].to_vec();
+        let mode = TreeMode::DEFAULT;
+
+        let proof = t.prove(2);
+        assert!(proof.verify_leaf_hash(t.root_hash(), 5, 2, &leaf, mode, &mut hasher));
+        assert!(!proof.verify_leaf_hash(t.root_hash(), 5, 2, &short, mode, &mut hasher));
+        let mut path = proof.path().to_vec();
+        let mut long = path[0].to_vec();
+        long.push(0);
+        path[0] = Hash::from(long);
+        assert!(!InclusionProof::new(mode, 5, path).verify_leaf_hash(
+            t.root_hash(),
+            5,
+            2,
+            &leaf,
+            mode,
+            &mut hasher
+        ));
+
+        let proof = t.prove_many(&[2]);
+        assert!(!proof.verify_leaf_hashes(t.root_hash(), 5, &[2], &[short], mode, &mut hasher));
+
+        let old: MerkleTree = MerkleTree::build(&["a", "b", "c"]);
+        let proof = t.consistency_proof(3);
+        let old_root = &old.root_hash()[1..];
+        assert!(!proof.verify(old_root, 3, t.root_hash(), 5, mode, &mut hasher));
+    }
+
+    #[test]
+    fn test_multi_proof_verifies_any_subset_of_leaves() {
+        let values = ["a", "b", "c", "d", "e", "f", "g"];
+        let subsets: [&[usize]; 5] = [&[0], &[6], &[1, 2], &[5, 0, 3], &[0, 1, 2, 3, 4, 5, 6]];
+        for &mode in MODES.iter() {
+            let t = build(&values, mode);
+            for positions in subsets.iter() {
+                let proof = t.prove_many(positions);
+                let subset: Vec<&str> = positions.iter().map(|&p| values[p]).collect();
+                let mut hasher = DefaultHasher::new();
+                assert!(proof.verify(t.root_hash(), 7, positions, &subset, mode, &mut hasher));
+            }
+        }
+    }
+
+    #[test]
+    fn test_multi_proof_does_not_repeat_shared_hashes() {
+        let values = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        let t: MerkleTree = MerkleTree::build(&values);
+
+        assert_eq!(t.prove_many(&[0, 1]).hashes().len(), 2);
+        assert_eq!(t.prove_many(&[0, 2]).hashes().len(), 3);
+        assert_eq!(t.prove_many(&[0, 7]).hashes().len(), 4);
+        assert!(t.prove_many(&[0, 1, 2, 3, 4, 5, 6, 7]).hashes().is_empty());
+    }
+
+    #[test]
+    fn test_multi_proof_rejects_wrong_values() {
+        let values = ["a", "b", "c", "d", "e"];
+        let t: MerkleTree = MerkleTree::build(&values);
+        let proof = t.prove_many(&[1, 4]);
+        let mut hasher = DefaultHasher::new();
+        let mode = TreeMode::DEFAULT;
+
+        assert!(proof.verify(t.root_hash(), 5, &[4, 1], &["e", "b"], mode, &mut hasher));
+        assert!(!proof.verify(t.root_hash(), 5, &[1, 4], &["e", "b"], mode, &mut hasher));
+        assert!(!proof.verify(t.root_hash(), 5, &[1, 3], &["b", "d"], mode, &mut hasher));
+        assert!(!proof.verify(t.root_hash(), 5, &[1, 1], &["b", "x"], mode, &mut hasher));
+        assert!(!proof.verify(t.root_hash(), 5, &[1], &["b"], mode, &mut hasher));
+    }
+
+    #[test]
+    fn test_consistency_proof_between_every_pair_of_sizes() {
+        let values = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k"];
+        let mut hasher = DefaultHasher::new();
+        for &mode in MODES.iter() {
+            for new_size in 0..values.len() + 1 {
+                let new = build(&values[..new_size], mode);
+                for old_size in 0..new_size + 1 {
+                    let old = build(&values[..old_size], mode);
+                    let proof = new.consistency_proof(old_size);
+                    assert!(proof.verify(
+                        old.root_hash(),
+                        old_size,
+                        new.root_hash(),
+                        new_size,
+                        mode,
+                        &mut hasher
+                    ));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_consistency_proof_rejects_rewritten_history() {
+        let old: MerkleTree = MerkleTree::build(&["a", "b", "c"]);
+        let new: MerkleTree = MerkleTree::build(&["a", "b", "c", "d", "e"]);
+        let rewritten: MerkleTree = MerkleTree::build(&["a", "x", "c", "d", "e"]);
+        let mut hasher = DefaultHasher::new();
+        let mode = TreeMode::DEFAULT;
+
+        let proof = rewritten.consistency_proof(3);
+        assert!(!proof.verify(
+            old.root_hash(),
+            3,
+            rewritten.root_hash(),
+            5,
+            mode,
+            &mut hasher
+        ));
+
+        let proof = new.consistency_proof(3);
+        assert!(proof.verify(old.root_hash(), 3, new.root_hash(), 5, mode, &mut hasher));
+        assert!(!proof.verify(old.root_hash(), 2, new.root_hash(), 5, mode, &mut hasher));
+        assert!(!proof.verify(old.root_hash(), 3, new.root_hash(), 9, mode, &mut hasher));
+        assert!(!proof.verify(new.root_hash(), 3, old.root_hash(), 5, mode, &mut hasher));
+    }
+
+    // Test vectors shared by the Certificate Transparency reference
+    // implementations: eight leaves and the Merkle Tree Hash of every prefix.
+    const RFC6962_LEAVES: [&str; 8] = [
+        "",
+        "00",
+        "10",
+        "2021",
+        "3031",
+        "40414243",
+        "5051525354555657",
+        "606162636465666768696a6b6c6d6e6f",
+    ];
+    const RFC6962_ROOTS: [&str; 8] = [
+        "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+        "fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125",
+        "aeb6bcfe274b70a14fb067a5e5578264db0fa9b51af5e0ba159158f329e06e77",
+        "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+        "4e3bbb1f7b478dcfe71fb631631519a3bca12c9aefca1612bfce4c13a86264d4",
+        "76e67dadbcdf1e10e1b74ddc608abd2f98dfb16fbce75277b5232a127f2087ef",
+        "ddb89be403809e325750d3d263cd78929c2942b7942a34b77e122c9594a74c8c",
+        "5dc9da79a70659a9ad559cb701ded9a2ab9d823aad2f4960cfe370eff4604328",
+    ];
+
+    fn from_hex(hashes: &[&str]) -> Vec<Hash> {
+        hashes.iter().map(|h| h.parse().unwrap()).collect()
+    }
+
+    fn rfc6962_tree(count: usize) -> MerkleTree {
+        let leaves = from_hex(&RFC6962_LEAVES[..count]);
+        let values: Vec<&[u8]> = leaves.iter().map(|l| l.as_bytes()).collect();
+        MerkleTree::build_with_mode(&values, DefaultHasher::new(), TreeMode::RFC6962)
+    }
+
+    #[test]
+    fn test_rfc6962_roots_match_the_reference_vectors() {
+        for count in 1..RFC6962_LEAVES.len() + 1 {
+            assert_eq!(
+                rfc6962_tree(count).root_hash_str(),
+                RFC6962_ROOTS[count - 1]
+            );
+        }
+    }
+
+    #[test]
+    fn test_rfc6962_audit_paths_match_the_reference_vectors() {
+        let vectors: [(usize, usize, &[&str]); 5] = [
+            (0, 1, &[]),
+            (
+                0,
+                8,
+                &[
+                    "96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7",
+                    "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                    "6b47aaf29ee3c2af9af889bc1fb9254dabd31177f16232dd6aab035ca39bf6e4",
+                ],
+            ),
+            (
+                5,
+                8,
+                &[
+                    "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+                    "ca854ea128ed050b41b35ffc1b87b8eb2bde461e9e3b5596ece6b9d5975a0ae0",
+                    "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+                ],
+            ),
+            (
+                2,
+                3,
+                &["fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125"],
+            ),
+            (
+                1,
+                5,
+                &[
+                    "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+                    "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                    "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+                ],
+            ),
+        ];
+        let mut hasher = DefaultHasher::new();
+        for &(leaf_index, tree_size, audit_path) in vectors.iter() {
+            let t = rfc6962_tree(tree_size);
+            let root = RFC6962_ROOTS[tree_size - 1].parse::<Hash>().unwrap();
+            let leaf = RFC6962_LEAVES[leaf_index].parse::<Hash>().unwrap();
+            let proof = InclusionProof::new(TreeMode::RFC6962, tree_size, from_hex(audit_path));
+
+            assert_eq!(t.prove(leaf_index), proof);
+            let mode = TreeMode::RFC6962;
+            let leaf = leaf.as_bytes();
+            assert!(proof.verify(&root, tree_size, leaf_index, &leaf, mode, &mut hasher));
+        }
+    }
+
+    #[test]
+    fn test_rfc6962_consistency_proofs_match_the_reference_vectors() {
+        let vectors: [(usize, usize, &[&str]); 4] = [
+            (1, 1, &[]),
+            (
+                1,
+                8,
+                &[
+                    "96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7",
+                    "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                    "6b47aaf29ee3c2af9af889bc1fb9254dabd31177f16232dd6aab035ca39bf6e4",
+                ],
+            ),
+            (
+                6,
+                8,
+                &[
+                    "0ebc5d3437fbe2db158b9f126a1d118e308181031d0a949f8dededebc558ef6a",
+                    "ca854ea128ed050b41b35ffc1b87b8eb2bde461e9e3b5596ece6b9d5975a0ae0",
+                    "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+                ],
+            ),
+            (
+                2,
+                5,
+                &[
+                    "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                    "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+                ],
+            ),
+        ];
+        let mut hasher = DefaultHasher::new();
+        for &(first, second, consistency) in vectors.iter() {
+            let first_hash = RFC6962_ROOTS[first - 1].parse::<Hash>().unwrap();
+            let second_hash = RFC6962_ROOTS[second - 1].parse::<Hash>().unwrap();
+            let proof = ConsistencyProof::new(TreeMode::RFC6962, from_hex(consistency));
+
+            assert_eq!(rfc6962_tree(second).consistency_proof(first), proof);
+            let mode = TreeMode::RFC6962;
+            assert!(proof.verify(&first_hash, first, &second_hash, second, mode, &mut hasher));
+            if first != second {
+                assert!(!proof.verify(&second_hash, first, &first_hash, second, mode, &mut hasher));
+            }
+        }
+    }
+}
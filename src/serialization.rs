@@ -0,0 +1,171 @@
+// Serde support for trees
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{Hash, HashAlgorithm, MerkleError, MerkleTree, TreeMode};
+
+/// A tree is serialized as its hash algorithm, mode, leaf hashes and root
+/// hash. The internal nodes are rebuilt on deserialization and the root must
+/// match the serialized one.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "MerkleTree")]
+struct TreeFields {
+    algorithm: u8,
+    mode: TreeMode,
+    leaves: Vec<Hash>,
+    root: Hash,
+}
+
+impl<H> Serialize for MerkleTree<H>
+where
+    H: HashAlgorithm,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        TreeFields {
+            algorithm: H::ID,
+            mode: self.mode,
+            leaves: self.leaves().iter().map(|l| l.as_slice().into()).collect(),
+            root: self.root_hash().into(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, H> Deserialize<'de> for MerkleTree<H>
+where
+    H: HashAlgorithm + Default,
+{
+    fn deserialize<D>(deserializer: D) -> Result<MerkleTree<H>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let fields = TreeFields::deserialize(deserializer)?;
+        if fields.algorithm != H::ID {
+            return Err(D::Error::custom(MerkleError::AlgorithmMismatch {
+                expected: H::ID,
+                received: fields.algorithm,
+            }));
+        }
+
+        let tree =
+            MerkleTree::try_build_from_leaves_with_mode(&fields.leaves, H::default(), fields.mode)
+                .map_err(D::Error::custom)?;
+        if tree.root_hash() != fields.root.as_bytes() {
+            return Err(D::Error::custom(MerkleError::Malformed(
+                "root hash does not match the leaves",
+            )));
+        }
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::bitcoin::DoubleSha256;
+    use super::super::{
+        ConsistencyProof, DefaultHasher, Hash, InclusionProof, MerkleTree, MultiProof, TreeMode,
+    };
+
+    fn build(count: usize, mode: TreeMode) -> MerkleTree {
+        let values: Vec<String> = (0..count).map(|i| i.to_string()).collect();
+        MerkleTree::build_with_mode(&values, DefaultHasher::new(), mode)
+    }
+
+    #[test]
+    fn test_hashes_are_hex_in_json_and_bytes_in_bincode() {
+        let hash = Hash::from(vec![0xde, 0xad, 0xbe, 0xef]);
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, "\"deadbeef\"");
+        assert_eq!(serde_json::from_str::<Hash>(&json).unwrap(), hash);
+
+        let binary = bincode::serialize(&hash).unwrap();
+        assert_eq!(&binary[8..], &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(bincode::deserialize::<Hash>(&binary).unwrap(), hash);
+
+        assert!(serde_json::from_str::<Hash>("\"not hex\"").is_err());
+        assert!(serde_json::from_str::<Hash>("\"de ad\\nbeef\"").is_err());
+    }
+
+    #[test]
+    fn test_trees_round_trip() {
+        for &mode in [TreeMode::DEFAULT, TreeMode::RFC6962, TreeMode::BLAKE3].iter() {
+            for count in 0..12 {
+                let t = build(count, mode);
+
+                let json = serde_json::to_string(&t).unwrap();
+                let decoded: MerkleTree = serde_json::from_str(&json).unwrap();
+                assert_eq!(decoded.root_hash(), t.root_hash());
+                assert_eq!(decoded.mode(), mode);
+
+                let binary = bincode::serialize(&t).unwrap();
+                let decoded: MerkleTree = bincode::deserialize(&binary).unwrap();
+                assert_eq!(decoded.leaves(), t.leaves());
+                assert_eq!(decoded.root_hash(), t.root_hash());
+            }
+        }
+    }
+
+    #[test]
+    fn test_sha3_and_blake2_trees_round_trip() {
+        let values = ["a", "b", "c"];
+        let t: MerkleTree<sha3::Keccak256> = MerkleTree::build(&values);
+        let json = serde_json::to_string(&t).unwrap();
+        let decoded: MerkleTree<sha3::Keccak256> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.root_hash(), t.root_hash());
+        assert!(serde_json::from_str::<MerkleTree<sha3::Sha3_256>>(&json).is_err());
+
+        let t: MerkleTree<blake2::Blake2s256> = MerkleTree::build(&values);
+        let binary = bincode::serialize(&t).unwrap();
+        let decoded: MerkleTree<blake2::Blake2s256> = bincode::deserialize(&binary).unwrap();
+        assert_eq!(decoded.root_hash(), t.root_hash());
+    }
+
+    #[test]
+    fn test_inconsistent_trees_are_rejected() {
+        let t = build(5, TreeMode::DEFAULT);
+        let mut json: serde_json::Value = serde_json::to_value(&t).unwrap();
+        json["leaves"][0] = json["leaves"][1].clone();
+        assert!(serde_json::from_value::<MerkleTree>(json).is_err());
+
+        let json = serde_json::to_string(&t).unwrap();
+        assert!(serde_json::from_str::<MerkleTree<DoubleSha256>>(&json).is_err());
+    }
+
+    #[test]
+    fn test_proofs_round_trip() {
+        let t = build(11, TreeMode::RFC6962);
+
+        let proof = t.prove(6);
+        let json = serde_json::to_string(&proof).unwrap();
+        assert_eq!(
+            serde_json::from_str::<InclusionProof>(&json).unwrap(),
+            proof
+        );
+        let binary = bincode::serialize(&proof).unwrap();
+        assert_eq!(
+            bincode::deserialize::<InclusionProof>(&binary).unwrap(),
+            proof
+        );
+
+        let proof = t.prove_many(&[1, 2, 9]);
+        let json = serde_json::to_string(&proof).unwrap();
+        assert_eq!(serde_json::from_str::<MultiProof>(&json).unwrap(), proof);
+        let binary = bincode::serialize(&proof).unwrap();
+        assert_eq!(bincode::deserialize::<MultiProof>(&binary).unwrap(), proof);
+
+        let proof = t.consistency_proof(5);
+        let json = serde_json::to_string(&proof).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ConsistencyProof>(&json).unwrap(),
+            proof
+        );
+        let binary = bincode::serialize(&proof).unwrap();
+        assert_eq!(
+            bincode::deserialize::<ConsistencyProof>(&binary).unwrap(),
+            proof
+        );
+    }
+}
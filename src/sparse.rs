@@ -0,0 +1,391 @@
+// Sparse Merkle Tree
+use digest::{Digest, FixedOutputReset, Output};
+use std::collections::{BTreeMap, HashMap};
+
+use super::hash::ct_eq;
+use super::hasher::{hash_empty, hash_internal_node, hash_keyed_leaf};
+use super::{AsBytes, DefaultHasher, Hash};
+
+/// Number of bits of a key, and so the height of a `SparseMerkleTree`.
+pub const KEY_BITS: usize = 256;
+
+/// Key of a `SparseMerkleTree`, read as a big endian integer: its most
+/// significant bit picks the child of the root, its least significant one
+/// the leaf.
+pub type Key = [u8; 32];
+
+/// Authenticated map from 256-bit keys to values, held as a Merkle tree with
+/// a leaf for every possible key.
+///
+/// Leaves of present keys hash their key and value like the leaves of a
+/// domain separated `MerkleTree`, the leaves of absent keys are the hash of
+/// nothing. All but a few of the `2^256` subtrees are then empty, and their
+/// hash only depends on their height, so those default hashes are computed
+/// once and only the nodes above present keys are stored.
+#[derive(Debug, Clone)]
+pub struct SparseMerkleTree<H = DefaultHasher>
+where
+    H: Digest + FixedOutputReset,
+{
+    hasher: H,
+    /// Hash of an empty subtree of height `height` at index `height`.
+    defaults: Vec<Output<H>>,
+    values: BTreeMap<Key, Vec<u8>>,
+    /// Nodes that differ from the default hash of their height, by height
+    /// and by their key prefix, i.e. any of their keys with the `height`
+    /// lowest bits cleared.
+    nodes: HashMap<(usize, Key), Output<H>>,
+}
+
+impl<H> SparseMerkleTree<H>
+where
+    H: Digest + FixedOutputReset,
+{
+    pub fn new() -> SparseMerkleTree<H>
+    where
+        H: Default,
+    {
+        SparseMerkleTree::with_hasher(Default::default())
+    }
+
+    pub fn with_hasher(mut hasher: H) -> SparseMerkleTree<H> {
+        let defaults = default_hashes(&mut hasher);
+        SparseMerkleTree {
+            hasher,
+            defaults,
+            values: BTreeMap::new(),
+            nodes: HashMap::new(),
+        }
+    }
+
+    /// Number of keys present.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn root_hash(&self) -> &[u8] {
+        self.node(KEY_BITS, &[0; 32])
+    }
+
+    pub fn get(&self, key: &Key) -> Option<&[u8]> {
+        self.values.get(key).map(Vec::as_slice)
+    }
+
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.values.contains_key(key)
+    }
+
+    /// Present keys with their values, in increasing order of keys.
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &[u8])> {
+        self.values
+            .iter()
+            .map(|(key, value)| (key, value.as_slice()))
+    }
+
+    /// Sets the value of `key`, inserting it or updating it, and returns its
+    /// previous value.
+    pub fn insert<T>(&mut self, key: Key, value: &T) -> Option<Vec<u8>>
+    where
+        T: AsBytes,
+    {
+        let leaf = hash_keyed_leaf(&key, value.as_bytes(), &mut self.hasher);
+        self.update_path(&key, leaf);
+        self.values.insert(key, value.as_bytes().to_vec())
+    }
+
+    /// Deletes `key`, whose leaf becomes empty again, and returns its value.
+    pub fn remove(&mut self, key: &Key) -> Option<Vec<u8>> {
+        let value = self.values.remove(key)?;
+        let leaf = self.defaults[0].clone();
+        self.update_path(key, leaf);
+        Some(value)
+    }
+
+    /// Returns the siblings of the leaf of `key`, which prove either that the
+    /// key holds its value or, if it is absent, that it holds none.
+    pub fn prove(&self, key: &Key) -> SparseProof {
+        let mut bitmap = [0u8; 32];
+        let mut siblings = Vec::new();
+        let mut prefix = *key;
+        for height in 0..KEY_BITS {
+            flip_bit(&mut prefix, height);
+            if let Some(sibling) = self.nodes.get(&(height, prefix)) {
+                flip_bit(&mut bitmap, height);
+                siblings.push(sibling.as_slice().into());
+            }
+            clear_bit(&mut prefix, height);
+        }
+        SparseProof { bitmap, siblings }
+    }
+
+    fn node(&self, height: usize, prefix: &Key) -> &Output<H> {
+        self.nodes
+            .get(&(height, *prefix))
+            .unwrap_or(&self.defaults[height])
+    }
+
+    /// Sets the leaf of `key` and rehashes every node above it, forgetting
+    /// those that are back to their default hash.
+    fn update_path(&mut self, key: &Key, leaf: Output<H>) {
+        let mut prefix = *key;
+        let mut node = leaf;
+        for height in 0..KEY_BITS {
+            let mut sibling_prefix = prefix;
+            flip_bit(&mut sibling_prefix, height);
+            let parent = {
+                let sibling = self.node(height, &sibling_prefix).clone();
+                if bit(key, height) {
+                    hash_internal_node(&sibling, Some(&node), &mut self.hasher)
+                } else {
+                    hash_internal_node(&node, Some(&sibling), &mut self.hasher)
+                }
+            };
+            self.set_node(height, prefix, node);
+            clear_bit(&mut prefix, height);
+            node = parent;
+        }
+        self.set_node(KEY_BITS, prefix, node);
+    }
+
+    fn set_node(&mut self, height: usize, prefix: Key, node: Output<H>) {
+        if node == self.defaults[height] {
+            self.nodes.remove(&(height, prefix));
+        } else {
+            self.nodes.insert((height, prefix), node);
+        }
+    }
+}
+
+impl<H> Default for SparseMerkleTree<H>
+where
+    H: Digest + FixedOutputReset + Default,
+{
+    fn default() -> SparseMerkleTree<H> {
+        SparseMerkleTree::new()
+    }
+}
+
+/// Siblings of a leaf of a `SparseMerkleTree`, from the leaf up to the root.
+///
+/// Only the siblings that are not the hash of an empty subtree are kept; bit
+/// `height` of `bitmap`, counting from the least significant bit of its last
+/// byte like the bits of a key, is set when the sibling at that height is
+/// among `siblings`. The same proof shows that a key holds a value or that it
+/// is absent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SparseProof {
+    bitmap: [u8; 32],
+    siblings: Vec<Hash>,
+}
+
+impl SparseProof {
+    /// Assembles a proof received from elsewhere.
+    pub fn new(bitmap: [u8; 32], siblings: Vec<Hash>) -> SparseProof {
+        SparseProof { bitmap, siblings }
+    }
+
+    pub fn bitmap(&self) -> &[u8; 32] {
+        &self.bitmap
+    }
+
+    /// Siblings that are not the hash of an empty subtree, ordered from the
+    /// leaf up to the root.
+    pub fn siblings(&self) -> &[Hash] {
+        &self.siblings
+    }
+
+    /// Checks that `key` holds `value` in the tree with `root`.
+    pub fn verify<T, H>(&self, root: &[u8], key: &Key, value: &T, hasher: &mut H) -> bool
+    where
+        T: AsBytes,
+        H: Digest + FixedOutputReset,
+    {
+        let leaf = hash_keyed_leaf(key, value.as_bytes(), hasher);
+        self.verify_leaf(root, key, leaf, hasher)
+    }
+
+    /// Checks that `key` is absent from the tree with `root`.
+    pub fn verify_non_inclusion<H>(&self, root: &[u8], key: &Key, hasher: &mut H) -> bool
+    where
+        H: Digest + FixedOutputReset,
+    {
+        let leaf = hash_empty(hasher);
+        self.verify_leaf(root, key, leaf, hasher)
+    }
+
+    fn verify_leaf<H>(&self, root: &[u8], key: &Key, leaf: Output<H>, hasher: &mut H) -> bool
+    where
+        H: Digest + FixedOutputReset,
+    {
+        let len = <H as Digest>::output_size();
+        if self.siblings.iter().any(|s| s.len() != len) {
+            return false;
+        }
+
+        let mut siblings = self.siblings.iter();
+        let mut default = hash_empty(hasher);
+        let mut node = leaf;
+        for height in 0..KEY_BITS {
+            let sibling: &[u8] = if bit(&self.bitmap, height) {
+                match siblings.next() {
+                    Some(sibling) => sibling,
+                    None => return false,
+                }
+            } else {
+                &default
+            };
+            node = if bit(key, height) {
+                hash_internal_node(sibling, Some(&node), hasher)
+            } else {
+                hash_internal_node(&node, Some(sibling), hasher)
+            };
+            default = hash_internal_node(&default, None, hasher);
+        }
+        siblings.next().is_none() && ct_eq(&node, root)
+    }
+}
+
+/// Hashes of the empty subtrees of every height, from the empty leaf up to
+/// the root of an empty tree.
+fn default_hashes<H>(hasher: &mut H) -> Vec<Output<H>>
+where
+    H: Digest + FixedOutputReset,
+{
+    let mut defaults = Vec::with_capacity(KEY_BITS + 1);
+    defaults.push(hash_empty(hasher));
+    for height in 0..KEY_BITS {
+        let node = hash_internal_node(&defaults[height], None, hasher);
+        defaults.push(node);
+    }
+    defaults
+}
+
+fn bit(bits: &[u8; 32], index: usize) -> bool {
+    bits[31 - index / 8] >> (index % 8) & 1 == 1
+}
+
+fn flip_bit(bits: &mut [u8; 32], index: usize) {
+    bits[31 - index / 8] ^= 1 << (index % 8);
+}
+
+fn clear_bit(bits: &mut [u8; 32], index: usize) {
+    bits[31 - index / 8] &= !(1 << (index % 8));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{DefaultHasher, Digest, Hash};
+    use super::{Key, SparseMerkleTree, SparseProof, KEY_BITS};
+
+    fn key(n: u8) -> Key {
+        let mut key = [0u8; 32];
+        key[0] = n;
+        key[31] = n;
+        key
+    }
+
+    fn hashed_key(value: &str) -> Key {
+        DefaultHasher::digest(value.as_bytes()).into()
+    }
+
+    #[test]
+    fn test_root_depends_on_contents_only() {
+        let empty = SparseMerkleTree::<DefaultHasher>::new();
+        let mut t = SparseMerkleTree::<DefaultHasher>::new();
+        assert_eq!(t.root_hash(), empty.root_hash());
+
+        t.insert(key(1), &"one");
+        t.insert(key(2), &"two");
+        let mut other = SparseMerkleTree::<DefaultHasher>::new();
+        other.insert(key(2), &"two");
+        other.insert(key(1), &"one");
+        assert_eq!(t.root_hash(), other.root_hash());
+
+        assert_eq!(t.insert(key(1), &"uno"), Some(b"one".to_vec()));
+        assert_ne!(t.root_hash(), other.root_hash());
+        assert_eq!(t.get(&key(1)), Some(&b"uno"[..]));
+
+        assert_eq!(t.remove(&key(1)), Some(b"uno".to_vec()));
+        assert_eq!(t.remove(&key(2)), Some(b"two".to_vec()));
+        assert_eq!(t.remove(&key(2)), None);
+        assert!(t.is_empty());
+        assert_eq!(t.root_hash(), empty.root_hash());
+        assert!(t.nodes.is_empty());
+    }
+
+    #[test]
+    fn test_inclusion_proofs() {
+        let mut t = SparseMerkleTree::<DefaultHasher>::new();
+        let values = ["a", "b", "c", "d", "e"];
+        for value in values.iter() {
+            t.insert(hashed_key(value), value);
+        }
+
+        let mut hasher = DefaultHasher::new();
+        for value in values.iter() {
+            let proof = t.prove(&hashed_key(value));
+            assert!(proof.verify(t.root_hash(), &hashed_key(value), value, &mut hasher));
+            assert!(!proof.verify(t.root_hash(), &hashed_key(value), &"z", &mut hasher));
+            assert!(!proof.verify_non_inclusion(t.root_hash(), &hashed_key(value), &mut hasher));
+            assert!(!proof.verify(t.root_hash(), &hashed_key("z"), value, &mut hasher));
+        }
+    }
+
+    #[test]
+    fn test_non_inclusion_proofs() {
+        let mut t = SparseMerkleTree::<DefaultHasher>::new();
+        let mut hasher = DefaultHasher::new();
+        let proof = t.prove(&key(3));
+        assert!(proof.siblings().is_empty());
+        assert!(proof.verify_non_inclusion(t.root_hash(), &key(3), &mut hasher));
+
+        t.insert(key(1), &"one");
+        t.insert(key(2), &"two");
+        let proof = t.prove(&key(3));
+        assert_eq!(proof.siblings().len(), 2);
+        assert!(proof.verify_non_inclusion(t.root_hash(), &key(3), &mut hasher));
+        assert!(!proof.verify(t.root_hash(), &key(3), &"three", &mut hasher));
+
+        let proof = t.prove(&key(1));
+        assert!(!proof.verify_non_inclusion(t.root_hash(), &key(1), &mut hasher));
+    }
+
+    #[test]
+    fn test_tampered_proofs_are_rejected() {
+        let mut t = SparseMerkleTree::<DefaultHasher>::new();
+        t.insert(key(1), &"one");
+        t.insert(key(2), &"two");
+        let mut hasher = DefaultHasher::new();
+        let proof = t.prove(&key(1));
+
+        let mut bitmap = *proof.bitmap();
+        bitmap[0] |= 0x80;
+        let tampered = SparseProof::new(bitmap, proof.siblings().to_vec());
+        assert!(!tampered.verify(t.root_hash(), &key(1), &"one", &mut hasher));
+
+        let mut siblings = proof.siblings().to_vec();
+        siblings.push(Hash::from(vec![0; 32]));
+        let tampered = SparseProof::new(*proof.bitmap(), siblings);
+        assert!(!tampered.verify(t.root_hash(), &key(1), &"one", &mut hasher));
+
+        let tampered = SparseProof::new(*proof.bitmap(), vec![Hash::from(vec![0; 31])]);
+        assert!(!tampered.verify(t.root_hash(), &key(1), &"one", &mut hasher));
+    }
+
+    #[test]
+    fn test_only_nodes_above_present_keys_are_stored() {
+        let mut t = SparseMerkleTree::<DefaultHasher>::new();
+        for n in 0..10 {
+            t.insert(hashed_key(&n.to_string()), &"value");
+        }
+        assert_eq!(t.len(), 10);
+        assert!(t.nodes.len() <= 10 * (KEY_BITS + 1));
+        assert_eq!(t.iter().count(), 10);
+        assert!(t.iter().zip(t.iter().skip(1)).all(|(a, b)| a.0 < b.0));
+    }
+}
@@ -0,0 +1,763 @@
+// Storage of the nodes of trees
+//
+// The file of a `FileStore` starts with a header, followed by a slot for the
+// record of every node, see `record_offset`. Integers are little endian.
+//
+//   magic       4 bytes   "MRKS"
+//   version     1 byte    `FORMAT_VERSION`
+//   hash length 1 byte
+//
+// Each record is the level of the node plus one, 1 byte, its index within the
+// level, 8 bytes, then its hash. The slots of the nodes not put yet are either
+// past the end of the file or filled with zeros, so that their first byte is
+// zero.
+use digest::{Digest, Output};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use super::encoding::read_array;
+use super::{MerkleError, FORMAT_VERSION};
+
+const MAGIC: [u8; 4] = *b"MRKS";
+const HEADER_LEN: usize = 4 + 1 + 1;
+
+/// Where a `MerkleTree` keeps its nodes, addressed by level, counting from
+/// the leaves up, and by index within the level.
+///
+/// A tree of `n` leaves puts the nodes `0..w` of every level, where `w` is
+/// `n` on the leaf level and halves, rounding up, on every level above. The
+/// root of an empty tree is not stored.
+pub trait NodeStore<H>
+where
+    H: Digest,
+{
+    /// Returns the node at `index` of `level`, if it was put.
+    fn get(&self, level: usize, index: usize) -> Result<Option<Output<H>>, MerkleError>;
+
+    /// Stores `node` at `index` of `level`, replacing the node there if any.
+    fn put(&mut self, level: usize, index: usize, node: Output<H>) -> Result<(), MerkleError>;
+
+    /// Stores `nodes` at the consecutive indices of `level` from `start`.
+    /// Trees are built by putting their levels in batches, which stores
+    /// should write at once where they can.
+    fn put_batch(
+        &mut self,
+        level: usize,
+        start: usize,
+        nodes: Vec<Output<H>>,
+    ) -> Result<(), MerkleError> {
+        for (index, node) in (start..).zip(nodes) {
+            self.put(level, index, node)?;
+        }
+        Ok(())
+    }
+
+    /// Stores each of `nodes` at the level and index it comes with. Pushing
+    /// or updating a leaf puts its whole path at once, of which stores that
+    /// can fail should keep either every node or none, so that the tree
+    /// stays as it was when the push fails.
+    fn put_nodes(&mut self, nodes: Vec<(usize, usize, Output<H>)>) -> Result<(), MerkleError> {
+        for (level, index, node) in nodes {
+            self.put(level, index, node)?;
+        }
+        Ok(())
+    }
+
+    /// Number of nodes of `level`, one more than the highest index put.
+    fn width(&self, level: usize) -> Result<usize, MerkleError>;
+}
+
+/// Nodes held in memory, one vector per level. This is the store of trees
+/// that are not given another one.
+pub struct MemoryStore<H>
+where
+    H: Digest,
+{
+    levels: Vec<Vec<Output<H>>>,
+}
+
+impl<H> MemoryStore<H>
+where
+    H: Digest,
+{
+    pub fn new() -> MemoryStore<H> {
+        MemoryStore { levels: Vec::new() }
+    }
+
+    /// Nodes of `level`, empty if the tree is not that high.
+    pub fn level(&self, level: usize) -> &[Output<H>] {
+        self.levels.get(level).map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns the nodes of `level` with room for `index`.
+    fn level_mut(&mut self, level: usize, index: usize) -> &mut Vec<Output<H>> {
+        if self.levels.len() <= level {
+            self.levels.resize_with(level + 1, Vec::new);
+        }
+        let nodes = &mut self.levels[level];
+        if nodes.len() <= index {
+            nodes.resize(index + 1, Output::<H>::default());
+        }
+        nodes
+    }
+}
+
+impl<H> NodeStore<H> for MemoryStore<H>
+where
+    H: Digest,
+{
+    fn get(&self, level: usize, index: usize) -> Result<Option<Output<H>>, MerkleError> {
+        Ok(self.level(level).get(index).cloned())
+    }
+
+    fn put(&mut self, level: usize, index: usize, node: Output<H>) -> Result<(), MerkleError> {
+        self.level_mut(level, index)[index] = node;
+        Ok(())
+    }
+
+    fn put_batch(
+        &mut self,
+        level: usize,
+        start: usize,
+        nodes: Vec<Output<H>>,
+    ) -> Result<(), MerkleError> {
+        if nodes.is_empty() {
+            return Ok(());
+        }
+        if start == 0 && self.level(level).len() <= nodes.len() {
+            self.level_mut(level, 0);
+            self.levels[level] = nodes;
+            return Ok(());
+        }
+        let end = start + nodes.len();
+        self.level_mut(level, end - 1)[start..end].clone_from_slice(&nodes);
+        Ok(())
+    }
+
+    fn width(&self, level: usize) -> Result<usize, MerkleError> {
+        Ok(self.level(level).len())
+    }
+}
+
+impl<H> Default for MemoryStore<H>
+where
+    H: Digest,
+{
+    fn default() -> MemoryStore<H> {
+        MemoryStore::new()
+    }
+}
+
+impl<H> Clone for MemoryStore<H>
+where
+    H: Digest,
+{
+    fn clone(&self) -> MemoryStore<H> {
+        MemoryStore {
+            levels: self.levels.clone(),
+        }
+    }
+}
+
+impl<H> PartialEq for MemoryStore<H>
+where
+    H: Digest,
+{
+    fn eq(&self, other: &MemoryStore<H>) -> bool {
+        self.levels == other.levels
+    }
+}
+
+impl<H> Eq for MemoryStore<H> where H: Digest {}
+
+impl<H> fmt::Debug for MemoryStore<H>
+where
+    H: Digest,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let widths: Vec<usize> = self.levels.iter().map(Vec::len).collect();
+        f.debug_struct("MemoryStore")
+            .field("widths", &widths)
+            .finish()
+    }
+}
+
+/// Nodes kept in a file, so that a tree survives restarts and need not fit in
+/// memory. Only the width of every level is held in memory: the record of a
+/// node is found from its level and index.
+///
+/// Updating a node overwrites its record. A record cut short by a crash at
+/// the end of the file is dropped when the file is opened.
+///
+/// Reads seek before reading, so the file sits behind a mutex to keep
+/// concurrent readers from moving it under each other.
+///
+/// A failed write is undone before the error is returned. If that fails too,
+/// the records may no longer be those of a single tree, and the store refuses
+/// every later read and write.
+#[derive(Debug)]
+pub struct FileStore<H>
+where
+    H: Digest,
+{
+    file: Mutex<File>,
+    len: u64,
+    widths: Vec<usize>,
+    poisoned: bool,
+    hasher: PhantomData<H>,
+}
+
+impl<H> FileStore<H>
+where
+    H: Digest,
+{
+    /// Opens the store at `path`, creating it if it does not exist, and
+    /// finds the width of every level it holds.
+    pub fn open<P>(path: P) -> Result<FileStore<H>, MerkleError>
+    where
+        P: AsRef<Path>,
+    {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let hash_len = <H as Digest>::output_size();
+        let file_len = file.metadata()?.len();
+        if file_len == 0 {
+            file.write_all(&MAGIC)?;
+            file.write_all(&[FORMAT_VERSION, hash_len as u8])?;
+        }
+
+        let mut reader = BufReader::new(&file);
+        reader.seek(SeekFrom::Start(0))?;
+        let mut header = [0u8; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        let mut input = &header[..];
+        if read_array(&mut input)? != MAGIC {
+            return Err(MerkleError::Malformed("not a node store"));
+        }
+        let [version, stored_hash_len] = read_array(&mut input)?;
+        if version != FORMAT_VERSION {
+            return Err(MerkleError::UnsupportedVersion(version));
+        }
+        if stored_hash_len as usize != hash_len {
+            return Err(MerkleError::Malformed(
+                "hash length does not match the hasher",
+            ));
+        }
+
+        let record_len = record_len::<H>();
+        let mut widths = Vec::new();
+        let mut len = HEADER_LEN as u64;
+        let mut record = vec![0u8; record_len as usize];
+        while len + record_len <= file_len {
+            reader.read_exact(&mut record)?;
+            if let Some((level, index)) = read_record_key(&record)? {
+                if record_offset::<H>(level, index) != Some(len) {
+                    return Err(MerkleError::Malformed("record is not in its slot"));
+                }
+                if widths.len() <= level {
+                    widths.resize(level + 1, 0);
+                }
+                widths[level] = widths[level].max(index + 1);
+            }
+            len += record_len;
+        }
+        drop(reader);
+        if len < file_len {
+            file.set_len(len)?;
+        }
+
+        Ok(FileStore {
+            file: Mutex::new(file),
+            len,
+            widths,
+            poisoned: false,
+            hasher: PhantomData,
+        })
+    }
+
+    /// Flushes the records written so far to the disk.
+    pub fn sync(&self) -> Result<(), MerkleError> {
+        self.check_poisoned()?;
+        Ok(self.lock().sync_data()?)
+    }
+
+    /// The file, even if a reader panicked while holding it: every read seeks
+    /// first, so the position it left behind does not matter.
+    fn lock(&self) -> MutexGuard<'_, File> {
+        self.file.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn check_poisoned(&self) -> Result<(), MerkleError> {
+        if self.poisoned {
+            Err(io::Error::other("a failed write to the node store could not be undone").into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes the record of each of `nodes` into its slot, and notes the new
+    /// widths only once every write succeeded. Otherwise the records that
+    /// were overwritten are put back and those past the end of the file are
+    /// cut off.
+    fn write_records<I>(&mut self, nodes: I) -> Result<(), MerkleError>
+    where
+        I: IntoIterator<Item = (usize, usize, Output<H>)>,
+    {
+        self.check_poisoned()?;
+        let record_len = record_len::<H>();
+        let mut records = Vec::new();
+        let mut len = self.len;
+        for (level, index, node) in nodes {
+            let offset = record_offset::<H>(level, index)
+                .ok_or(MerkleError::Malformed("node does not fit in a file"))?;
+            let mut record = Vec::with_capacity(record_len as usize);
+            record.push(level as u8 + 1);
+            record.extend_from_slice(&(index as u64).to_le_bytes());
+            record.extend_from_slice(&node);
+            len = len.max(offset + record_len);
+            records.push((level, index, offset, record));
+        }
+
+        let file = self.file.get_mut().unwrap_or_else(|e| e.into_inner());
+        let mut overwritten = Vec::new();
+        for (_, _, offset, record) in &records {
+            let written = write_record(file, *offset, record, self.len, &mut overwritten);
+            if let Err(error) = written {
+                let undone = overwritten
+                    .iter()
+                    .rev()
+                    .try_for_each(|(offset, old)| write_at(file, *offset, old))
+                    .and_then(|()| file.set_len(self.len));
+                if undone.is_err() {
+                    self.poisoned = true;
+                }
+                return Err(error.into());
+            }
+        }
+
+        for (level, index, _, _) in records {
+            if self.widths.len() <= level {
+                self.widths.resize(level + 1, 0);
+            }
+            self.widths[level] = self.widths[level].max(index + 1);
+        }
+        self.len = len;
+        Ok(())
+    }
+}
+
+impl<H> NodeStore<H> for FileStore<H>
+where
+    H: Digest,
+{
+    fn get(&self, level: usize, index: usize) -> Result<Option<Output<H>>, MerkleError> {
+        self.check_poisoned()?;
+        let offset = match record_offset::<H>(level, index) {
+            Some(offset) if offset + record_len::<H>() <= self.len => offset,
+            _ => return Ok(None),
+        };
+        let mut record = vec![0u8; record_len::<H>() as usize];
+        let mut file = self.lock();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut record)?;
+        Ok(match read_record_key(&record)? {
+            Some(_) => Some(Output::<H>::clone_from_slice(&record[9..])),
+            None => None,
+        })
+    }
+
+    fn put(&mut self, level: usize, index: usize, node: Output<H>) -> Result<(), MerkleError> {
+        self.write_records([(level, index, node)])
+    }
+
+    /// Either writes all the records or none of them.
+    fn put_batch(
+        &mut self,
+        level: usize,
+        start: usize,
+        nodes: Vec<Output<H>>,
+    ) -> Result<(), MerkleError> {
+        self.write_records(
+            (start..)
+                .zip(nodes)
+                .map(|(index, node)| (level, index, node)),
+        )
+    }
+
+    /// Either writes all the records or none of them, as `put_batch`.
+    fn put_nodes(&mut self, nodes: Vec<(usize, usize, Output<H>)>) -> Result<(), MerkleError> {
+        self.write_records(nodes)
+    }
+
+    fn width(&self, level: usize) -> Result<usize, MerkleError> {
+        Ok(self.widths.get(level).copied().unwrap_or(0))
+    }
+}
+
+fn record_len<H>() -> u64
+where
+    H: Digest,
+{
+    (1 + 8 + <H as Digest>::output_size()) as u64
+}
+
+/// Offset of the slot of the node at `index` of `level`, or `None` if it
+/// would not fit in a file.
+///
+/// The slots follow the order in which appending leaves one at a time
+/// completes the nodes: the node is over `m` leaves counting those before
+/// it, so it comes after the `2m - ones(m)` nodes of a range of `m` leaves
+/// but before the `zeros(m) - level` ancestors completed with it, where
+/// `ones` counts the bits of `m` that are set and `zeros` the trailing
+/// zeros.
+fn record_offset<H>(level: usize, index: usize) -> Option<u64>
+where
+    H: Digest,
+{
+    if level >= u8::MAX as usize {
+        return None;
+    }
+    let end = (index as u64).checked_add(1)?;
+    let covered = end
+        .checked_shl(level as u32)
+        .filter(|m| m >> level == end)?;
+    let slot = covered
+        .checked_mul(2)?
+        .checked_sub(u64::from(covered.count_ones() + covered.trailing_zeros()) + 1)?
+        + level as u64;
+    slot.checked_mul(record_len::<H>())?
+        .checked_add(HEADER_LEN as u64)
+}
+
+/// Level and index of the node of `record`, `None` for a slot nothing was
+/// written to.
+fn read_record_key(record: &[u8]) -> Result<Option<(usize, usize)>, MerkleError> {
+    if record[0] == 0 {
+        return Ok(None);
+    }
+    let index = u64::from_le_bytes(record[1..9].try_into().unwrap());
+    let index = usize::try_from(index)
+        .map_err(|_| MerkleError::Malformed("length does not fit in memory"))?;
+    Ok(Some((record[0] as usize - 1, index)))
+}
+
+/// Writes `record` at `offset`, first saving the record it replaces if it is
+/// before `len`, the end of the file.
+fn write_record(
+    file: &mut File,
+    offset: u64,
+    record: &[u8],
+    len: u64,
+    overwritten: &mut Vec<(u64, Vec<u8>)>,
+) -> io::Result<()> {
+    if offset < len {
+        let mut old = vec![0u8; record.len()];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut old)?;
+        overwritten.push((offset, old));
+    }
+    write_at(file, offset, record)
+}
+
+fn write_at(file: &mut File, offset: u64, bytes: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{DefaultHasher, MerkleError, MerkleTree, TreeMode};
+    use super::{record_len, record_offset, FileStore, MemoryStore, NodeStore, HEADER_LEN};
+    use digest::Output;
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io;
+    use std::path::PathBuf;
+    use std::thread;
+
+    const MODES: [TreeMode; 4] = [
+        TreeMode::DEFAULT,
+        TreeMode::RFC6962,
+        TreeMode::BITCOIN,
+        TreeMode::BLAKE3,
+    ];
+
+    /// Path of a fresh file in the temporary directory.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("merkletree-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_memory_and_file_stores_give_the_same_roots() {
+        let mut values: Vec<String> = (0..23).map(|i| i.to_string()).collect();
+        for (i, &mode) in MODES.iter().enumerate() {
+            let path = temp_path(&format!("same-roots-{}", i));
+            let store = FileStore::<DefaultHasher>::open(&path).unwrap();
+            let mut in_file =
+                MerkleTree::try_build_with_store(&values[..9], DefaultHasher::new(), mode, store)
+                    .unwrap();
+            let mut in_memory: MerkleTree =
+                MerkleTree::build_with_mode(&values[..9], DefaultHasher::new(), mode);
+            assert_eq!(in_file.root_hash(), in_memory.root_hash());
+
+            for value in &values[9..] {
+                in_file.push(value);
+                in_memory.push(value);
+                assert_eq!(in_file.root_hash(), in_memory.root_hash());
+            }
+            values[4] = "x".to_string();
+            assert_eq!(in_file.update(4, &"x"), in_memory.update(4, &"x"));
+            assert_eq!(in_file.prove(17), in_memory.prove(17));
+            assert_eq!(in_file.consistency_proof(9), in_memory.consistency_proof(9));
+            assert_eq!(
+                in_file.prove_many(&[0, 4, 22]),
+                in_memory.prove_many(&[0, 4, 22])
+            );
+            assert!(in_file.verify(4, &"x"));
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_reopened_file_store_holds_the_same_tree() {
+        let values: Vec<String> = (0..13).map(|i| i.to_string()).collect();
+        let path = temp_path("reopened");
+        let store = FileStore::<DefaultHasher>::open(&path).unwrap();
+        let mut t = MerkleTree::try_build_with_store(
+            &values[..8],
+            DefaultHasher::new(),
+            TreeMode::DEFAULT,
+            store,
+        )
+        .unwrap();
+        t.extend(&values[8..]);
+        t.update(2, &"x");
+        let root = t.root_hash().to_vec();
+        t.into_store().sync().unwrap();
+
+        let store = FileStore::<DefaultHasher>::open(&path).unwrap();
+        let mut reopened =
+            MerkleTree::try_from_store(store, DefaultHasher::new(), TreeMode::DEFAULT).unwrap();
+        assert_eq!(reopened.count_leaves(), values.len());
+        assert_eq!(reopened.root_hash(), root.as_slice());
+        assert!(reopened.verify(2, &"x"));
+
+        reopened.push(&"13");
+        let mut in_memory: MerkleTree = MerkleTree::build(&values);
+        in_memory.update(2, &"x");
+        in_memory.push(&"13");
+        assert_eq!(reopened.root_hash(), in_memory.root_hash());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_partial_trailing_record_is_dropped() {
+        let path = temp_path("partial");
+        let mut store = FileStore::<DefaultHasher>::open(&path).unwrap();
+        store.put(0, 0, Default::default()).unwrap();
+        store.put(0, 1, Default::default()).unwrap();
+        drop(store);
+
+        let record_len = 1 + 8 + 32;
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len((HEADER_LEN + record_len + 5) as u64).unwrap();
+        drop(file);
+
+        let store = FileStore::<DefaultHasher>::open(&path).unwrap();
+        assert_eq!(store.width(0).unwrap(), 1);
+        assert_eq!(store.get(0, 1).unwrap(), None);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            (HEADER_LEN + record_len) as u64
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_poisoned_file_store_refuses_reads_and_writes() {
+        let path = temp_path("poisoned");
+        let mut store = FileStore::<DefaultHasher>::open(&path).unwrap();
+        store.put(0, 0, [1; 32].into()).unwrap();
+        store.poisoned = true;
+        assert!(store.put(0, 1, Default::default()).is_err());
+        assert!(store.put_batch(1, 0, vec![Default::default()]).is_err());
+        assert!(store.get(0, 0).is_err());
+        assert_eq!(store.width(0).unwrap(), 1);
+        drop(store);
+
+        let store = FileStore::<DefaultHasher>::open(&path).unwrap();
+        assert_eq!(store.width(0).unwrap(), 1);
+        assert_eq!(store.get(0, 0).unwrap(), Some([1; 32].into()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_nodes_of_whole_trees_fill_the_slots_in_order() {
+        let record_len = record_len::<DefaultHasher>();
+        let mut slots = Vec::new();
+        for level in 0..=4 {
+            for index in 0..16 >> level {
+                let offset = record_offset::<DefaultHasher>(level, index).unwrap();
+                slots.push((offset - HEADER_LEN as u64) / record_len);
+            }
+        }
+        slots.sort_unstable();
+        assert_eq!(slots, (0..31).collect::<Vec<u64>>());
+        // Leaves 0 and 1, their parent, then leaf 2.
+        assert_eq!(
+            record_offset::<DefaultHasher>(0, 2),
+            Some(HEADER_LEN as u64 + 3 * record_len)
+        );
+        assert_eq!(
+            record_offset::<DefaultHasher>(1, 1),
+            Some(HEADER_LEN as u64 + 5 * record_len)
+        );
+        assert_eq!(record_offset::<DefaultHasher>(64, 0), None);
+        assert_eq!(record_offset::<DefaultHasher>(1, usize::MAX), None);
+    }
+
+    #[test]
+    fn test_file_store_only_keeps_widths_in_memory() {
+        let values: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        let path = temp_path("widths");
+        let store = FileStore::<DefaultHasher>::open(&path).unwrap();
+        let t = MerkleTree::try_build_with_store(
+            &values,
+            DefaultHasher::new(),
+            TreeMode::DEFAULT,
+            store,
+        )
+        .unwrap();
+        let store = t.into_store();
+        assert_eq!(store.widths, vec![100, 50, 25, 13, 7, 4, 2, 1]);
+        // A tree of 100 leaves has its slots among those of 128 leaves.
+        assert!(
+            fs::metadata(&path).unwrap().len()
+                <= HEADER_LEN as u64 + 255 * record_len::<DefaultHasher>()
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_readers_get_their_own_nodes() {
+        let path = temp_path("concurrent");
+        let mut store = FileStore::<DefaultHasher>::open(&path).unwrap();
+        let nodes: Vec<_> = (0..64u8).map(|i| [i; 32].into()).collect();
+        store.put_batch(0, 0, nodes.clone()).unwrap();
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        for (index, node) in nodes.iter().enumerate() {
+                            assert_eq!(store.get(0, index).unwrap().as_ref(), Some(node));
+                        }
+                    }
+                });
+            }
+        });
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Memory store whose paths cannot be put while `full` is set.
+    struct FullStore {
+        nodes: MemoryStore<DefaultHasher>,
+        full: bool,
+    }
+
+    impl NodeStore<DefaultHasher> for FullStore {
+        fn get(
+            &self,
+            level: usize,
+            index: usize,
+        ) -> Result<Option<Output<DefaultHasher>>, MerkleError> {
+            self.nodes.get(level, index)
+        }
+
+        fn put(
+            &mut self,
+            level: usize,
+            index: usize,
+            node: Output<DefaultHasher>,
+        ) -> Result<(), MerkleError> {
+            self.nodes.put(level, index, node)
+        }
+
+        fn put_nodes(
+            &mut self,
+            nodes: Vec<(usize, usize, Output<DefaultHasher>)>,
+        ) -> Result<(), MerkleError> {
+            if self.full {
+                return Err(io::Error::other("no space left").into());
+            }
+            self.nodes.put_nodes(nodes)
+        }
+
+        fn width(&self, level: usize) -> Result<usize, MerkleError> {
+            self.nodes.width(level)
+        }
+    }
+
+    #[test]
+    fn test_failed_pushes_leave_the_tree_as_it_was() {
+        let values: Vec<String> = (0..6).map(|i| i.to_string()).collect();
+        let store = FullStore {
+            nodes: MemoryStore::new(),
+            full: false,
+        };
+        let t = MerkleTree::try_build_with_store(
+            &values,
+            DefaultHasher::new(),
+            TreeMode::DEFAULT,
+            store,
+        )
+        .unwrap();
+        let root = t.root_hash().to_vec();
+        let nodes = t.store().nodes.clone();
+
+        let mut store = t.into_store();
+        store.full = true;
+        let mut t =
+            MerkleTree::try_from_store(store, DefaultHasher::new(), TreeMode::DEFAULT).unwrap();
+        assert!(t.try_push(&"6").is_err());
+        assert!(t.try_update(5, &"x").is_err());
+        assert_eq!(t.count_leaves(), values.len());
+        assert_eq!(t.root_hash(), root.as_slice());
+        assert!(t.store().nodes == nodes);
+
+        let mut store = t.into_store();
+        store.full = false;
+        let mut t =
+            MerkleTree::try_from_store(store, DefaultHasher::new(), TreeMode::DEFAULT).unwrap();
+        t.push(&"6");
+        let mut in_memory: MerkleTree = MerkleTree::build(&values);
+        in_memory.push(&"6");
+        assert_eq!(t.root_hash(), in_memory.root_hash());
+    }
+
+    #[test]
+    fn test_empty_trees_have_the_same_root_in_every_store() {
+        let path = temp_path("empty");
+        let store = FileStore::<DefaultHasher>::open(&path).unwrap();
+        let in_file =
+            MerkleTree::try_from_store(store, DefaultHasher::new(), TreeMode::DEFAULT).unwrap();
+        let in_memory =
+            MerkleTree::try_from_store(MemoryStore::new(), DefaultHasher::new(), TreeMode::DEFAULT)
+                .unwrap();
+        assert_eq!(in_file.root_hash(), in_memory.root_hash());
+        assert_eq!(
+            in_memory.root_hash(),
+            MerkleTree::<DefaultHasher>::build::<&str>(&[]).root_hash()
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
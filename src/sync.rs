@@ -0,0 +1,676 @@
+// Anti-entropy synchronization of two replicas
+//
+// A replica asks another one for its root, then for the hashes of the nodes
+// below the subtrees that differ, until it reaches the leaves. Each message is
+// framed as follows, integers being little endian.
+//
+//   length      4 bytes   number of bytes that follow
+//   version     1 byte    `FORMAT_VERSION`
+//   tag         1 byte    1 get root, 2 root, 3 get nodes, 4 nodes, 5 done
+//   ...                   fields of the message
+//
+// Fields by tag:
+//
+//   1 get root  none
+//   2 root      algorithm 1 byte, mode 1 byte as in encoded trees, leaves
+//               8 bytes, hash length 1 byte, root
+//   3 get nodes level 1 byte, ranges 8 bytes, then the start and end of every
+//               range of node indices, 8 bytes each
+//   4 nodes     hash length 1 byte, hashes 8 bytes, hashes
+//   5 done      none
+use digest::{Digest, FixedOutputReset};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+use super::encoding::{decode_mode, encode_mode, read_array, read_length};
+use super::tree::level_widths;
+use super::{Hash, HashAlgorithm, MerkleError, MerkleTree, TreeMode, FORMAT_VERSION};
+
+/// Largest message accepted, to bound the memory a peer can make us take.
+pub const MAX_MESSAGE_LEN: usize = 1 << 26;
+
+/// Levels a replica descends by in every round trip of `sync`.
+pub const DEFAULT_LEVELS_PER_ROUND: usize = 4;
+
+const TAG_GET_ROOT: u8 = 1;
+const TAG_ROOT: u8 = 2;
+const TAG_GET_NODES: u8 = 3;
+const TAG_NODES: u8 = 4;
+const TAG_DONE: u8 = 5;
+
+/// Message of the synchronization protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Asks for the root of the tree.
+    GetRoot,
+    /// Describes the tree, in answer to `GetRoot`.
+    Root {
+        algorithm: u8,
+        mode: TreeMode,
+        count_leaves: usize,
+        root: Hash,
+    },
+    /// Asks for the nodes of `level`, counting from the leaves up, at the
+    /// indices of `ranges`.
+    GetNodes {
+        level: usize,
+        ranges: Vec<Range<usize>>,
+    },
+    /// Node hashes in the order they were asked for, in answer to `GetNodes`.
+    Nodes { hashes: Vec<Hash> },
+    /// Ends the synchronization.
+    Done,
+}
+
+impl Message {
+    /// Encodes the message with its frame. Fails on levels above 255, on
+    /// hashes of different lengths or longer than 255 bytes, and on messages
+    /// longer than `MAX_MESSAGE_LEN`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MerkleError> {
+        let mut bytes = vec![0; 4];
+        bytes.push(FORMAT_VERSION);
+        match self {
+            Message::GetRoot => bytes.push(TAG_GET_ROOT),
+            Message::Root {
+                algorithm,
+                mode,
+                count_leaves,
+                root,
+            } => {
+                bytes.extend_from_slice(&[TAG_ROOT, *algorithm, encode_mode(*mode)]);
+                bytes.extend_from_slice(&(*count_leaves as u64).to_le_bytes());
+                write_hashes(&mut bytes, std::slice::from_ref(root), false)?;
+            }
+            Message::GetNodes { level, ranges } => {
+                if *level > u8::MAX as usize {
+                    return Err(MerkleError::Malformed("level is too high"));
+                }
+                bytes.extend_from_slice(&[TAG_GET_NODES, *level as u8]);
+                bytes.extend_from_slice(&(ranges.len() as u64).to_le_bytes());
+                for range in ranges {
+                    bytes.extend_from_slice(&(range.start as u64).to_le_bytes());
+                    bytes.extend_from_slice(&(range.end as u64).to_le_bytes());
+                }
+            }
+            Message::Nodes { hashes } => {
+                bytes.push(TAG_NODES);
+                write_hashes(&mut bytes, hashes, true)?;
+            }
+            Message::Done => bytes.push(TAG_DONE),
+        }
+
+        let len = bytes.len() - 4;
+        if len > MAX_MESSAGE_LEN {
+            return Err(MerkleError::Malformed("message is too long"));
+        }
+        bytes[..4].copy_from_slice(&(len as u32).to_le_bytes());
+        Ok(bytes)
+    }
+
+    /// Decodes a message encoded by `to_bytes`, frame included.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Message, MerkleError> {
+        let mut input = bytes;
+        let len = u32::from_le_bytes(read_array(&mut input)?) as usize;
+        if len != input.len() {
+            return Err(MerkleError::Malformed("frame length does not match"));
+        }
+        let [version, tag] = read_array(&mut input)?;
+        if version != FORMAT_VERSION {
+            return Err(MerkleError::UnsupportedVersion(version));
+        }
+
+        let message = match tag {
+            TAG_GET_ROOT => Message::GetRoot,
+            TAG_ROOT => {
+                let [algorithm, mode] = read_array(&mut input)?;
+                let mode = decode_mode(mode)?;
+                let count_leaves = read_length(&mut input)?;
+                let hash_len = read_array::<1>(&mut input)?[0] as usize;
+                if input.len() < hash_len {
+                    return Err(MerkleError::Malformed("truncated hashes"));
+                }
+                let (root, rest) = input.split_at(hash_len);
+                input = rest;
+                Message::Root {
+                    algorithm,
+                    mode,
+                    count_leaves,
+                    root: Hash::from(root),
+                }
+            }
+            TAG_GET_NODES => {
+                let level = read_array::<1>(&mut input)?[0] as usize;
+                let count = read_length(&mut input)?;
+                if count > input.len() / 16 {
+                    return Err(MerkleError::Malformed("truncated ranges"));
+                }
+                let mut ranges = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let start = read_length(&mut input)?;
+                    let end = read_length(&mut input)?;
+                    if start > end {
+                        return Err(MerkleError::Malformed("range ends before it starts"));
+                    }
+                    ranges.push(start..end);
+                }
+                Message::GetNodes { level, ranges }
+            }
+            TAG_NODES => {
+                let hash_len = read_array::<1>(&mut input)?[0] as usize;
+                let count = read_length(&mut input)?;
+                let hashes_len = count
+                    .checked_mul(hash_len)
+                    .ok_or(MerkleError::Malformed("truncated hashes"))?;
+                if hashes_len > input.len() {
+                    return Err(MerkleError::Malformed("truncated hashes"));
+                }
+                if hash_len == 0 && count > 0 {
+                    return Err(MerkleError::Malformed("empty hashes"));
+                }
+                let (hashes, rest) = input.split_at(hashes_len);
+                input = rest;
+                Message::Nodes {
+                    hashes: hashes.chunks(hash_len.max(1)).map(Hash::from).collect(),
+                }
+            }
+            TAG_DONE => Message::Done,
+            _ => return Err(MerkleError::Malformed("unknown message")),
+        };
+        if !input.is_empty() {
+            return Err(MerkleError::Malformed("trailing bytes"));
+        }
+        Ok(message)
+    }
+
+    /// Writes the message encoded by `to_bytes`. Nothing is written if it
+    /// cannot be encoded.
+    pub fn write_to<W>(&self, mut writer: W) -> Result<(), MerkleError>
+    where
+        W: Write,
+    {
+        writer.write_all(&self.to_bytes()?)?;
+        Ok(writer.flush()?)
+    }
+
+    /// Reads one message, failing with `io::ErrorKind::InvalidData` if it is
+    /// malformed or longer than `MAX_MESSAGE_LEN`.
+    pub fn read_from<R>(mut reader: R) -> io::Result<Message>
+    where
+        R: Read,
+    {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let count = u32::from_le_bytes(len) as usize;
+        if count > MAX_MESSAGE_LEN {
+            return Err(invalid_data(MerkleError::Malformed("message is too long")));
+        }
+
+        let mut bytes = len.to_vec();
+        bytes.resize(4 + count, 0);
+        reader.read_exact(&mut bytes[4..])?;
+        Message::from_bytes(&bytes).map_err(invalid_data)
+    }
+}
+
+/// Outcome of `sync`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncReport {
+    ranges: Vec<Range<usize>>,
+    round_trips: usize,
+    hashes_received: usize,
+}
+
+impl SyncReport {
+    /// Leaf positions at which the replicas differ, as `MerkleTree::diff`
+    /// would return them.
+    pub fn ranges(&self) -> &[Range<usize>] {
+        &self.ranges
+    }
+
+    /// Number of requests sent to the other replica.
+    pub fn round_trips(&self) -> usize {
+        self.round_trips
+    }
+
+    pub fn hashes_received(&self) -> usize {
+        self.hashes_received
+    }
+}
+
+/// Answers the requests of a replica calling `sync` over `stream` until it
+/// is done or closes the stream.
+pub fn serve<H, S>(tree: &MerkleTree<H>, mut stream: S) -> io::Result<()>
+where
+    H: HashAlgorithm,
+    S: Read + Write,
+{
+    let widths = level_widths(tree.count_leaves);
+    loop {
+        let request = match Message::read_from(&mut stream) {
+            Ok(request) => request,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let response = match request {
+            Message::GetRoot => Message::Root {
+                algorithm: H::ID,
+                mode: tree.mode,
+                count_leaves: tree.count_leaves,
+                root: tree.root_hash().into(),
+            },
+            Message::GetNodes { level, ranges } => {
+                let width = widths.get(level).copied().unwrap_or(0);
+                if ranges.iter().any(|range| range.end > width) {
+                    return Err(invalid_data(MerkleError::Malformed(
+                        "nodes out of the tree",
+                    )));
+                }
+                let count = ranges
+                    .iter()
+                    .fold(0usize, |sum, range| sum.saturating_add(range.len()));
+                if count > max_nodes_per_message::<H>() {
+                    return Err(invalid_data(MerkleError::Malformed(
+                        "too many nodes asked for at once",
+                    )));
+                }
+                let hashes = ranges
+                    .into_iter()
+                    .flatten()
+                    .map(|index| tree.node(level, index).as_slice().into())
+                    .collect();
+                Message::Nodes { hashes }
+            }
+            Message::Done => return Ok(()),
+            Message::Root { .. } | Message::Nodes { .. } => {
+                return Err(invalid_data(MerkleError::Malformed("unexpected response")))
+            }
+        };
+        response.write_to(&mut stream)?;
+    }
+}
+
+/// Finds the leaf positions at which `tree` differs from the tree of the
+/// replica served over `stream`, see `sync_with`.
+pub fn sync<H, S>(tree: &MerkleTree<H>, stream: S) -> io::Result<SyncReport>
+where
+    H: HashAlgorithm,
+    S: Read + Write,
+{
+    sync_with(tree, stream, DEFAULT_LEVELS_PER_ROUND)
+}
+
+/// Finds the leaf positions at which `tree` differs from the tree of the
+/// replica served over `stream`, then tells it that it is done.
+///
+/// Like `MerkleTree::diff`, only the subtrees whose roots differ are
+/// descended into. Each round trip asks for the nodes `levels_per_round`
+/// levels below all the differing nodes of the last round at once, so that
+/// the number of round trips only depends on the height of the trees, at the
+/// cost of fetching up to `2^levels_per_round` hashes per differing node.
+pub fn sync_with<H, S>(
+    tree: &MerkleTree<H>,
+    mut stream: S,
+    levels_per_round: usize,
+) -> io::Result<SyncReport>
+where
+    H: HashAlgorithm,
+    S: Read + Write,
+{
+    if levels_per_round == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "replicas must descend at least one level per round",
+        ));
+    }
+
+    let mut report = SyncReport {
+        ranges: Vec::new(),
+        round_trips: 1,
+        hashes_received: 1,
+    };
+    let (remote_count, remote_root) = match request(&mut stream, Message::GetRoot)? {
+        Message::Root {
+            algorithm,
+            mode,
+            count_leaves,
+            root,
+        } => {
+            if algorithm != H::ID {
+                return Err(invalid_data(MerkleError::AlgorithmMismatch {
+                    expected: H::ID,
+                    received: algorithm,
+                }));
+            }
+            if mode != tree.mode {
+                return Err(invalid_data(MerkleError::ModeMismatch {
+                    expected: tree.mode,
+                    received: mode,
+                }));
+            }
+            (count_leaves, root)
+        }
+        _ => return Err(unexpected_response()),
+    };
+
+    let local_count = tree.count_leaves;
+    let shared = local_count.min(remote_count);
+    let shared_height = level_widths(shared).len();
+    let count_leaves = local_count.max(remote_count);
+    let mut level = level_widths(count_leaves).len() - 1;
+    let mut differing = if local_count == remote_count && tree.root_hash() == &remote_root[..] {
+        Vec::new()
+    } else {
+        vec![0]
+    };
+
+    while level > 0 && !differing.is_empty() {
+        let lower = level.saturating_sub(levels_per_round);
+        let step = level - lower;
+
+        // Nodes past the leaves of either tree are left out, those over the
+        // end of the shorter tree differ without being compared.
+        let mut compared = Vec::new();
+        let mut next = Vec::new();
+        for index in differing {
+            for child in index << step..(index + 1) << step {
+                let start = child << lower;
+                let full_end = (child + 1) << lower;
+                if start >= shared {
+                    break;
+                }
+                if lower < shared_height && (full_end <= shared || local_count == remote_count) {
+                    compared.push(child);
+                } else {
+                    next.push(child);
+                }
+            }
+        }
+
+        for batch in compared.chunks(max_nodes_per_message::<H>()) {
+            let hashes = match request(
+                &mut stream,
+                Message::GetNodes {
+                    level: lower,
+                    ranges: to_ranges(batch),
+                },
+            )? {
+                Message::Nodes { hashes } if hashes.len() == batch.len() => hashes,
+                _ => return Err(unexpected_response()),
+            };
+            report.round_trips += 1;
+            report.hashes_received += hashes.len();
+            for (&index, hash) in batch.iter().zip(hashes) {
+                if tree.node(lower, index).as_slice() != hash.as_bytes() {
+                    next.push(index);
+                }
+            }
+        }
+        next.sort_unstable();
+        differing = next;
+        level = lower;
+    }
+
+    differing.retain(|&index| index < shared);
+    report.ranges = to_ranges(&differing);
+    if shared < count_leaves {
+        match report.ranges.last_mut() {
+            Some(last) if last.end == shared => last.end = count_leaves,
+            _ => report.ranges.push(shared..count_leaves),
+        }
+    }
+    Message::Done.write_to(&mut stream)?;
+    Ok(report)
+}
+
+fn request<S>(stream: &mut S, message: Message) -> io::Result<Message>
+where
+    S: Read + Write,
+{
+    message.write_to(&mut *stream)?;
+    Message::read_from(stream)
+}
+
+/// Number of hashes that fit in a `Nodes` message.
+fn max_nodes_per_message<H>() -> usize
+where
+    H: Digest + FixedOutputReset,
+{
+    (MAX_MESSAGE_LEN - 11) / <H as Digest>::output_size().max(1)
+}
+
+/// Groups increasing indices into ranges of consecutive ones.
+fn to_ranges(indices: &[usize]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for &index in indices {
+        match ranges.last_mut() {
+            Some(range) if range.end == index => range.end += 1,
+            _ => ranges.push(index..index + 1),
+        }
+    }
+    ranges
+}
+
+fn write_hashes(bytes: &mut Vec<u8>, hashes: &[Hash], with_count: bool) -> Result<(), MerkleError> {
+    let hash_len = hashes.first().map_or(0, |h| h.len());
+    if hash_len > u8::MAX as usize {
+        return Err(MerkleError::Malformed("hash is too long"));
+    }
+    if hashes.iter().any(|h| h.len() != hash_len) {
+        return Err(MerkleError::Malformed(
+            "hashes of a message must all have the same length",
+        ));
+    }
+    bytes.push(hash_len as u8);
+    if with_count {
+        bytes.extend_from_slice(&(hashes.len() as u64).to_le_bytes());
+    }
+    for hash in hashes {
+        bytes.extend_from_slice(hash);
+    }
+    Ok(())
+}
+
+fn invalid_data(error: MerkleError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn unexpected_response() -> io::Error {
+    invalid_data(MerkleError::Malformed("unexpected response"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{DefaultHasher, Hash, MerkleError, MerkleTree, TreeMode};
+    use super::{serve, sync, sync_with, Message, SyncReport};
+    use std::collections::VecDeque;
+    use std::io::{self, Read, Write};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+
+    /// One end of an in-memory duplex pipe.
+    struct Pipe {
+        sender: Sender<Vec<u8>>,
+        receiver: Receiver<Vec<u8>>,
+        buffer: VecDeque<u8>,
+    }
+
+    fn pipe() -> (Pipe, Pipe) {
+        let (left_sender, right_receiver) = channel();
+        let (right_sender, left_receiver) = channel();
+        let left = Pipe {
+            sender: left_sender,
+            receiver: left_receiver,
+            buffer: VecDeque::new(),
+        };
+        let right = Pipe {
+            sender: right_sender,
+            receiver: right_receiver,
+            buffer: VecDeque::new(),
+        };
+        (left, right)
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.buffer.is_empty() {
+                match self.receiver.recv() {
+                    Ok(bytes) => self.buffer.extend(bytes),
+                    Err(_) => return Ok(0),
+                }
+            }
+            self.buffer.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sender
+                .send(buf.to_vec())
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn build(values: &[String], mode: TreeMode) -> MerkleTree {
+        MerkleTree::build_with_mode(values, DefaultHasher::new(), mode)
+    }
+
+    fn sync_over_pipe(local: &MerkleTree, remote: &MerkleTree, levels: usize) -> SyncReport {
+        let (client, server) = pipe();
+        thread::scope(|scope| {
+            let handle = scope.spawn(move || serve(remote, server));
+            let report = sync_with(local, client, levels).unwrap();
+            handle.join().unwrap().unwrap();
+            report
+        })
+    }
+
+    #[test]
+    fn test_messages_round_trip() {
+        let messages = [
+            Message::GetRoot,
+            Message::Root {
+                algorithm: 1,
+                mode: TreeMode::RFC6962,
+                count_leaves: 12,
+                root: Hash::from(vec![7; 32]),
+            },
+            Message::GetNodes {
+                level: 3,
+                ranges: vec![0..2, 5..9],
+            },
+            Message::Nodes {
+                hashes: vec![Hash::from(vec![1; 32]), Hash::from(vec![2; 32])],
+            },
+            Message::Nodes { hashes: Vec::new() },
+            Message::Done,
+        ];
+        for message in messages.iter() {
+            let bytes = message.to_bytes().unwrap();
+            assert_eq!(&Message::from_bytes(&bytes).unwrap(), message);
+            assert_eq!(&Message::read_from(bytes.as_slice()).unwrap(), message);
+            assert!(Message::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        }
+
+        let mut bytes = Message::GetRoot.to_bytes().unwrap();
+        bytes[4] = 2;
+        assert_eq!(
+            Message::from_bytes(&bytes),
+            Err(MerkleError::UnsupportedVersion(2))
+        );
+        bytes[4] = 1;
+        bytes[5] = 9;
+        assert!(Message::from_bytes(&bytes).is_err());
+        let oversized = [0xff, 0xff, 0xff, 0xff, 1, 1];
+        assert!(Message::read_from(&oversized[..]).is_err());
+    }
+
+    #[test]
+    fn test_messages_that_cannot_be_encoded_are_rejected() {
+        let too_high = Message::GetNodes {
+            level: 256,
+            ranges: vec![0..1, 2..3],
+        };
+        assert!(too_high.to_bytes().is_err());
+        let mixed = Message::Nodes {
+            hashes: vec![Hash::from(vec![1; 32]), Hash::from(vec![2; 20])],
+        };
+        assert!(mixed.to_bytes().is_err());
+        let too_long = Message::Nodes {
+            hashes: vec![Hash::from(vec![1; 256])],
+        };
+        assert!(too_long.to_bytes().is_err());
+
+        let mut written = Vec::new();
+        assert!(mixed.write_to(&mut written).is_err());
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn test_syncing_without_descending_is_rejected() {
+        let tree = build(&["a".to_string()], TreeMode::DEFAULT);
+        let (client, _server) = pipe();
+        let error = sync_with(&tree, client, 0).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_replicas_sync_over_a_pipe() {
+        let values: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+        let mut changed = values.clone();
+        changed[3] = "x".to_string();
+        changed[517] = "y".to_string();
+        changed[518] = "z".to_string();
+        for &mode in [TreeMode::DEFAULT, TreeMode::RFC6962, TreeMode::BLAKE3].iter() {
+            let local = build(&values, mode);
+            for remote_len in [1000, 999, 700, 1000].iter() {
+                let remote = build(&changed[..*remote_len], mode);
+                let report = sync_over_pipe(&local, &remote, 4);
+                assert_eq!(report.ranges(), local.diff(&remote).ranges());
+                assert!(report.round_trips() <= 4);
+
+                let report = sync_over_pipe(&remote, &local, 4);
+                assert_eq!(report.ranges(), remote.diff(&local).ranges());
+            }
+
+            let report = sync_over_pipe(&local, &build(&values, mode), 4);
+            assert!(report.ranges().is_empty());
+            assert_eq!(report.round_trips(), 1);
+        }
+    }
+
+    #[test]
+    fn test_descending_further_per_round_saves_round_trips() {
+        let values: Vec<String> = (0..4096).map(|i| i.to_string()).collect();
+        let mut changed = values.clone();
+        changed[1234] = "x".to_string();
+        let local = build(&values, TreeMode::DEFAULT);
+        let remote = build(&changed, TreeMode::DEFAULT);
+
+        let one_level = sync_over_pipe(&local, &remote, 1);
+        let four_levels = sync_over_pipe(&local, &remote, 4);
+        assert_eq!(one_level.ranges(), local.diff(&remote).ranges());
+        assert_eq!(four_levels.ranges(), local.diff(&remote).ranges());
+        assert_eq!(four_levels.ranges().first(), Some(&(1234..1235)));
+        assert_eq!(one_level.round_trips(), 13);
+        assert_eq!(four_levels.round_trips(), 4);
+        assert!(one_level.hashes_received() < four_levels.hashes_received());
+    }
+
+    #[test]
+    fn test_replicas_with_different_modes_do_not_sync() {
+        let values: Vec<String> = (0..10).map(|i| i.to_string()).collect();
+        let local = build(&values, TreeMode::DEFAULT);
+        let remote = build(&values, TreeMode::RFC6962);
+        let (client, server) = pipe();
+        thread::scope(|scope| {
+            scope.spawn(move || serve(&remote, server));
+            let error = sync(&local, client).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        });
+    }
+}
@@ -0,0 +1,1065 @@
+// Merkle Tree construction, updates and proofs
+use digest::{Digest, FixedOutputReset, Output};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use std::mem;
+
+use super::blake3;
+use super::hash::ct_eq;
+use super::hasher::{hash_concat, hash_empty, hash_internal_node, hash_leaf};
+use super::{
+    AsBytes, ConsistencyProof, DefaultHasher, Hash, InclusionProof, MemoryStore, MerkleError,
+    MultiProof, NodeStore,
+};
+
+/// Builds the level above `nodes`, either `build_upper_level` or
+/// `par_build_upper_level`.
+type BuildLevel<H> = fn(&[Output<H>], TreeMode, &mut H) -> Result<Vec<Output<H>>, MerkleError>;
+
+/// Most nodes of a level that building a tree holds in memory before putting
+/// them into the store and hashing them into the level above. It is even, so
+/// that only the last batch of a level can end with a lone node.
+const BATCH_LEN: usize = 1 << 14;
+
+/// Fewest leaves or pairs of nodes a thread hashes at once when building in
+/// parallel.
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_LEN: usize = 1024;
+
+/// A Merkle tree whose nodes are kept in `S`, in memory unless built with
+/// another `NodeStore`. The root is also held by the tree itself.
+#[derive(Debug)]
+pub struct MerkleTree<H = DefaultHasher, S = MemoryStore<H>>
+where
+    H: Digest + FixedOutputReset,
+    S: NodeStore<H>,
+{
+    pub(crate) hasher: H,
+    pub(crate) mode: TreeMode,
+    pub(crate) store: S,
+    pub(crate) root: Output<H>,
+    pub(crate) count_leaves: usize,
+}
+
+/// How the last node of a level is carried to the level above when it has no
+/// sibling to be paired with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OddNodePolicy {
+    /// Hash the node with a copy of itself, as Bitcoin does. Trees over
+    /// `[a, b, c]` and `[a, b, c, c]` then share a root (CVE-2012-2459).
+    #[default]
+    Duplicate,
+    /// Move the node to the level above unchanged.
+    Promote,
+}
+
+/// How values and pairs of nodes are turned into hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Chaining {
+    /// Feed them to the hasher of the tree.
+    #[default]
+    Digest,
+    /// Hash values as BLAKE3 chunks at the offset of their leaf and pairs of
+    /// nodes as BLAKE3 parents, which yields the chaining values of BLAKE3's
+    /// own tree. The hasher of the tree is only used for empty trees.
+    Blake3,
+}
+
+/// Everything besides the hash function that decides the hashes of a tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TreeMode {
+    pub odd_node_policy: OddNodePolicy,
+    /// Prefix leaves with `LEAF_SIG` and internal nodes with `INTERNAL_SIG`
+    /// before hashing, so that a leaf can never pass for an internal node.
+    pub domain_separation: bool,
+    pub chaining: Chaining,
+}
+
+impl TreeMode {
+    /// Mode used unless another one is given.
+    pub const DEFAULT: TreeMode = TreeMode {
+        odd_node_policy: OddNodePolicy::Duplicate,
+        domain_separation: true,
+        chaining: Chaining::Digest,
+    };
+
+    /// Merkle Tree Hash of RFC 6962, used by Certificate Transparency logs
+    /// together with `DefaultHasher`. RFC 6962 splits `n` leaves into the
+    /// largest power of two smaller than `n` and the rest; pairing nodes left
+    /// to right and promoting lone ones builds exactly the same tree.
+    pub const RFC6962: TreeMode = TreeMode {
+        odd_node_policy: OddNodePolicy::Promote,
+        domain_separation: true,
+        chaining: Chaining::Digest,
+    };
+
+    /// Merkle root of a Bitcoin block, used together with
+    /// `bitcoin::DoubleSha256` over txids in internal byte order.
+    pub const BITCOIN: TreeMode = TreeMode {
+        odd_node_policy: OddNodePolicy::Duplicate,
+        domain_separation: false,
+        chaining: Chaining::Digest,
+    };
+
+    /// Tree of chaining values over the `blake3::CHUNK_LEN` byte chunks of a
+    /// file, see `blake3::Blake3Tree` for the BLAKE3 hash of the file.
+    pub const BLAKE3: TreeMode = TreeMode {
+        odd_node_policy: OddNodePolicy::Promote,
+        domain_separation: false,
+        chaining: Chaining::Blake3,
+    };
+
+    /// Hashes `value` as the leaf at `position`. In BLAKE3 chaining this
+    /// fails for values that are not a single chunk and for hashers whose
+    /// output is not a chaining value.
+    pub(crate) fn hash_leaf<T, H>(
+        self,
+        position: usize,
+        value: &T,
+        hasher: &mut H,
+    ) -> Result<Output<H>, MerkleError>
+    where
+        T: AsBytes,
+        H: Digest + FixedOutputReset,
+    {
+        if self.chaining == Chaining::Blake3 {
+            let output_size = <H as Digest>::output_size();
+            if output_size != ::blake3::OUT_LEN {
+                return Err(MerkleError::ChainingValueLength {
+                    received: output_size,
+                });
+            }
+            let cv = blake3::chunk_chaining_value(position, value.as_bytes())?;
+            Ok(Output::<H>::clone_from_slice(&cv))
+        } else if self.domain_separation {
+            Ok(hash_leaf(value, hasher))
+        } else {
+            Ok(hash_concat(&[value.as_bytes()], hasher))
+        }
+    }
+
+    pub(crate) fn hash_node<H>(
+        self,
+        left: &[u8],
+        right: &[u8],
+        hasher: &mut H,
+    ) -> Result<Output<H>, MerkleError>
+    where
+        H: Digest + FixedOutputReset,
+    {
+        if self.chaining == Chaining::Blake3 {
+            let cv = blake3::parent_chaining_value(left, right)?;
+            Ok(Output::<H>::clone_from_slice(&cv))
+        } else if self.domain_separation {
+            Ok(hash_internal_node(left, Some(right), hasher))
+        } else {
+            Ok(hash_concat(&[left, right], hasher))
+        }
+    }
+
+    pub(crate) fn hash_lone_node<H>(
+        self,
+        node: &Output<H>,
+        hasher: &mut H,
+    ) -> Result<Output<H>, MerkleError>
+    where
+        H: Digest + FixedOutputReset,
+    {
+        match self.odd_node_policy {
+            OddNodePolicy::Duplicate => self.hash_node(node, node, hasher),
+            OddNodePolicy::Promote => Ok(node.clone()),
+        }
+    }
+}
+
+impl Default for TreeMode {
+    fn default() -> TreeMode {
+        TreeMode::DEFAULT
+    }
+}
+
+pub(crate) fn build_upper_level<H>(
+    nodes: &[Output<H>],
+    mode: TreeMode,
+    hasher: &mut H,
+) -> Result<Vec<Output<H>>, MerkleError>
+where
+    H: Digest + FixedOutputReset,
+{
+    let mut row = Vec::with_capacity(nodes.len().div_ceil(2));
+    let mut i = 0;
+    while i < nodes.len() {
+        if i + 1 < nodes.len() {
+            row.push(mode.hash_node(&nodes[i], &nodes[i + 1], hasher)?);
+            i += 2;
+        } else {
+            row.push(mode.hash_lone_node(&nodes[i], hasher)?);
+            i += 1;
+        }
+    }
+
+    Ok(row)
+}
+
+/// Same as `build_upper_level`, but hashes the pairs of nodes across threads,
+/// each with its own clone of `hasher`.
+#[cfg(feature = "parallel")]
+fn par_build_upper_level<H>(
+    nodes: &[Output<H>],
+    mode: TreeMode,
+    hasher: &mut H,
+) -> Result<Vec<Output<H>>, MerkleError>
+where
+    H: Digest + FixedOutputReset + Clone + Send + Sync,
+{
+    let hasher = &*hasher;
+    nodes
+        .par_chunks(2)
+        .with_min_len(PARALLEL_MIN_LEN)
+        .map_init(
+            || hasher.clone(),
+            |hasher, pair| match pair {
+                [left, right] => mode.hash_node(left, right, hasher),
+                _ => mode.hash_lone_node(&pair[0], hasher),
+            },
+        )
+        .collect()
+}
+
+/// Returns the number of nodes on every level of a tree with `count_leaves`
+/// leaves, starting from the leaves and ending with the root.
+///
+/// Both odd node policies give the same widths, they only differ in how the
+/// last node of an odd level is hashed.
+pub(crate) fn level_widths(count_leaves: usize) -> Vec<usize> {
+    let mut widths = vec![count_leaves];
+    let mut width = count_leaves;
+    while width > 1 {
+        width = width.div_ceil(2);
+        widths.push(width);
+    }
+    widths
+}
+
+pub(crate) fn check_leaf_hash_length<H>(position: usize, leaf: &[u8]) -> Result<(), MerkleError>
+where
+    H: Digest + FixedOutputReset,
+{
+    let expected = <H as Digest>::output_size();
+    if leaf.len() == expected {
+        Ok(())
+    } else {
+        Err(MerkleError::LeafHashLengthMismatch {
+            position,
+            expected,
+            received: leaf.len(),
+        })
+    }
+}
+
+pub(crate) fn _build_from_leaves_with_hasher<H>(
+    leaves: &[Output<H>],
+    hasher: H,
+    mode: TreeMode,
+    build_level: BuildLevel<H>,
+) -> Result<MerkleTree<H>, MerkleError>
+where
+    H: Digest + FixedOutputReset,
+{
+    let leaf = |position: usize, _: &mut H| Ok(leaves[position].clone());
+    build_in_store(
+        MemoryStore::new(),
+        leaves.len(),
+        leaf,
+        hasher,
+        mode,
+        build_level,
+    )
+}
+
+/// Puts the `count_leaves` leaves given by `leaf` into `store`, along with
+/// every level above them. The levels are put in batches of `BATCH_LEN`
+/// nodes, each hashed into the level above as soon as it is full, so that
+/// no whole level is ever held in memory.
+///
+/// An empty tree stores nothing, its root is the hash of the empty string as
+/// in RFC 6962. A tree with a single leaf has no internal nodes, so that leaf
+/// is its root.
+fn build_in_store<H, S, F>(
+    mut store: S,
+    count_leaves: usize,
+    mut leaf: F,
+    mut hasher: H,
+    mode: TreeMode,
+    build_level: BuildLevel<H>,
+) -> Result<MerkleTree<H, S>, MerkleError>
+where
+    H: Digest + FixedOutputReset,
+    S: NodeStore<H>,
+    F: FnMut(usize, &mut H) -> Result<Output<H>, MerkleError>,
+{
+    if count_leaves == 0 {
+        let root = hash_empty(&mut hasher);
+        return Ok(MerkleTree {
+            hasher,
+            mode,
+            store,
+            root,
+            count_leaves,
+        });
+    }
+
+    let widths = level_widths(count_leaves);
+    let root_level = widths.len() - 1;
+    let mut pending: Vec<Vec<Output<H>>> = vec![Vec::new(); widths.len()];
+    let mut stored = vec![0; widths.len()];
+    let mut root = Output::<H>::default();
+    for position in 0..count_leaves {
+        pending[0].push(leaf(position, &mut hasher)?);
+        // Only the last leaf completes the levels, every one of them at once.
+        for level in 0..widths.len() {
+            let len = pending[level].len();
+            if len < BATCH_LEN && stored[level] + len < widths[level] {
+                break;
+            }
+            let nodes = mem::take(&mut pending[level]);
+            if level < root_level {
+                let upper = build_level(&nodes, mode, &mut hasher)?;
+                pending[level + 1].extend(upper);
+            } else {
+                root = nodes[0].clone();
+            }
+            store.put_batch(level, stored[level], nodes)?;
+            stored[level] += len;
+        }
+    }
+
+    Ok(MerkleTree {
+        hasher,
+        mode,
+        store,
+        root,
+        count_leaves,
+    })
+}
+
+impl<H> MerkleTree<H>
+where
+    H: Digest + FixedOutputReset,
+{
+    pub fn build<T>(values: &[T]) -> MerkleTree<H>
+    where
+        H: Default,
+        T: AsBytes,
+    {
+        MerkleTree::try_build(values).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_build<T>(values: &[T]) -> Result<MerkleTree<H>, MerkleError>
+    where
+        H: Default,
+        T: AsBytes,
+    {
+        let hasher = Default::default();
+        MerkleTree::try_build_with_hasher(values, hasher)
+    }
+
+    pub fn build_with_hasher<T>(values: &[T], hasher: H) -> MerkleTree<H>
+    where
+        T: AsBytes,
+    {
+        MerkleTree::try_build_with_hasher(values, hasher).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_build_with_hasher<T>(values: &[T], hasher: H) -> Result<MerkleTree<H>, MerkleError>
+    where
+        T: AsBytes,
+    {
+        MerkleTree::try_build_with_mode(values, hasher, TreeMode::DEFAULT)
+    }
+
+    pub fn build_with_mode<T>(values: &[T], hasher: H, mode: TreeMode) -> MerkleTree<H>
+    where
+        T: AsBytes,
+    {
+        MerkleTree::try_build_with_mode(values, hasher, mode).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_build_with_mode<T>(
+        values: &[T],
+        mut hasher: H,
+        mode: TreeMode,
+    ) -> Result<MerkleTree<H>, MerkleError>
+    where
+        T: AsBytes,
+    {
+        let leaves = values
+            .iter()
+            .enumerate()
+            .map(|(position, v)| mode.hash_leaf(position, v, &mut hasher))
+            .collect::<Result<Vec<Output<H>>, MerkleError>>()?;
+
+        _build_from_leaves_with_hasher(&leaves, hasher, mode, build_upper_level)
+    }
+
+    /// Same as `build`, but hashes the leaves and the nodes of each level
+    /// across threads.
+    #[cfg(feature = "parallel")]
+    pub fn par_build<T>(values: &[T]) -> MerkleTree<H>
+    where
+        H: Default + Clone + Send + Sync,
+        T: AsBytes + Sync,
+    {
+        MerkleTree::try_par_build(values).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    #[cfg(feature = "parallel")]
+    pub fn try_par_build<T>(values: &[T]) -> Result<MerkleTree<H>, MerkleError>
+    where
+        H: Default + Clone + Send + Sync,
+        T: AsBytes + Sync,
+    {
+        MerkleTree::try_par_build_with_mode(values, Default::default(), TreeMode::DEFAULT)
+    }
+
+    /// Same as `build_with_mode`, but hashes the leaves and the nodes of each
+    /// level across threads, each with its own clone of `hasher`.
+    #[cfg(feature = "parallel")]
+    pub fn par_build_with_mode<T>(values: &[T], hasher: H, mode: TreeMode) -> MerkleTree<H>
+    where
+        H: Clone + Send + Sync,
+        T: AsBytes + Sync,
+    {
+        MerkleTree::try_par_build_with_mode(values, hasher, mode)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    #[cfg(feature = "parallel")]
+    pub fn try_par_build_with_mode<T>(
+        values: &[T],
+        hasher: H,
+        mode: TreeMode,
+    ) -> Result<MerkleTree<H>, MerkleError>
+    where
+        H: Clone + Send + Sync,
+        T: AsBytes + Sync,
+    {
+        let leaves = values
+            .par_iter()
+            .with_min_len(PARALLEL_MIN_LEN)
+            .enumerate()
+            .map_init(
+                || hasher.clone(),
+                |hasher, (position, v)| mode.hash_leaf(position, v, hasher),
+            )
+            .collect::<Result<Vec<Output<H>>, MerkleError>>()?;
+
+        _build_from_leaves_with_hasher(&leaves, hasher, mode, par_build_upper_level)
+    }
+
+    pub fn build_from_leaves<L>(leaves: &[L]) -> MerkleTree<H>
+    where
+        H: Default,
+        L: AsRef<[u8]>,
+    {
+        MerkleTree::try_build_from_leaves(leaves).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_build_from_leaves<L>(leaves: &[L]) -> Result<MerkleTree<H>, MerkleError>
+    where
+        H: Default,
+        L: AsRef<[u8]>,
+    {
+        let hasher = Default::default();
+        MerkleTree::try_build_from_leaves_with_hasher(leaves, hasher)
+    }
+
+    pub fn build_from_leaves_with_hasher<L>(leaves: &[L], hasher: H) -> MerkleTree<H>
+    where
+        L: AsRef<[u8]>,
+    {
+        MerkleTree::try_build_from_leaves_with_hasher(leaves, hasher)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_build_from_leaves_with_hasher<L>(
+        leaves: &[L],
+        hasher: H,
+    ) -> Result<MerkleTree<H>, MerkleError>
+    where
+        L: AsRef<[u8]>,
+    {
+        MerkleTree::try_build_from_leaves_with_mode(leaves, hasher, TreeMode::DEFAULT)
+    }
+
+    pub fn build_from_leaves_with_mode<L>(leaves: &[L], hasher: H, mode: TreeMode) -> MerkleTree<H>
+    where
+        L: AsRef<[u8]>,
+    {
+        MerkleTree::try_build_from_leaves_with_mode(leaves, hasher, mode)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_build_from_leaves_with_mode<L>(
+        leaves: &[L],
+        hasher: H,
+        mode: TreeMode,
+    ) -> Result<MerkleTree<H>, MerkleError>
+    where
+        L: AsRef<[u8]>,
+    {
+        let mut hashes = Vec::with_capacity(leaves.len());
+        for (position, leaf) in leaves.iter().enumerate() {
+            check_leaf_hash_length::<H>(position, leaf.as_ref())?;
+            hashes.push(Output::<H>::clone_from_slice(leaf.as_ref()));
+        }
+
+        _build_from_leaves_with_hasher(&hashes, hasher, mode, build_upper_level)
+    }
+
+    /// Hashes of the leaves, in order.
+    pub fn leaves(&self) -> &[Output<H>] {
+        self.store.level(0)
+    }
+}
+
+impl<H, S> MerkleTree<H, S>
+where
+    H: Digest + FixedOutputReset,
+    S: NodeStore<H>,
+{
+    /// Same as `try_build_with_mode`, but puts the nodes into `store`, which
+    /// should be empty. The values are hashed as the leaves are put, so if one
+    /// of them cannot be hashed, the store keeps the nodes put before it.
+    pub fn try_build_with_store<T>(
+        values: &[T],
+        hasher: H,
+        mode: TreeMode,
+        store: S,
+    ) -> Result<MerkleTree<H, S>, MerkleError>
+    where
+        T: AsBytes,
+    {
+        let leaf =
+            |position: usize, hasher: &mut H| mode.hash_leaf(position, &values[position], hasher);
+        build_in_store(store, values.len(), leaf, hasher, mode, build_upper_level)
+    }
+
+    /// Same as `try_build_from_leaves_with_mode`, but puts the nodes into
+    /// `store`, which should be empty.
+    pub fn try_build_from_leaves_with_store<L>(
+        leaves: &[L],
+        hasher: H,
+        mode: TreeMode,
+        store: S,
+    ) -> Result<MerkleTree<H, S>, MerkleError>
+    where
+        L: AsRef<[u8]>,
+    {
+        for (position, leaf) in leaves.iter().enumerate() {
+            check_leaf_hash_length::<H>(position, leaf.as_ref())?;
+        }
+
+        let leaf = |position: usize, _: &mut H| {
+            Ok(Output::<H>::clone_from_slice(leaves[position].as_ref()))
+        };
+        build_in_store(store, leaves.len(), leaf, hasher, mode, build_upper_level)
+    }
+
+    /// Takes up the tree held by `store`, e.g. a reopened `FileStore`. The
+    /// leaves are those put on level 0 and the root is read from the store,
+    /// so `mode` must be the one the tree was built with.
+    pub fn try_from_store(
+        store: S,
+        mut hasher: H,
+        mode: TreeMode,
+    ) -> Result<MerkleTree<H, S>, MerkleError> {
+        let count_leaves = store.width(0)?;
+        let root = if count_leaves == 0 {
+            hash_empty(&mut hasher)
+        } else {
+            let depth = level_widths(count_leaves).len() - 1;
+            if store.width(depth)? != 1 {
+                return Err(MerkleError::Malformed("store does not hold a whole tree"));
+            }
+            store
+                .get(depth, 0)?
+                .ok_or(MerkleError::Malformed("node missing from the store"))?
+        };
+
+        Ok(MerkleTree {
+            hasher,
+            mode,
+            store,
+            root,
+            count_leaves,
+        })
+    }
+
+    /// Returns the store holding the nodes of the tree.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    pub fn count_leaves(&self) -> usize {
+        self.count_leaves
+    }
+
+    /// Returns how leaves and internal nodes of the tree are hashed.
+    pub fn mode(&self) -> TreeMode {
+        self.mode
+    }
+
+    /// Appends a leaf and recomputes only the hashes on its path to the root.
+    pub fn push<T>(&mut self, value: &T)
+    where
+        T: AsBytes,
+    {
+        self.try_push(value).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_push<T>(&mut self, value: &T) -> Result<(), MerkleError>
+    where
+        T: AsBytes,
+    {
+        let leaf = self
+            .mode
+            .hash_leaf(self.count_leaves, value, &mut self.hasher)?;
+        self.count_leaves += 1;
+        let pushed = self.rehash_path(self.count_leaves - 1, leaf);
+        if pushed.is_err() {
+            self.count_leaves -= 1;
+        }
+        pushed
+    }
+
+    /// Appends several leaves, see `push`.
+    pub fn extend<T>(&mut self, values: &[T])
+    where
+        T: AsBytes,
+    {
+        self.try_extend(values).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_extend<T>(&mut self, values: &[T]) -> Result<(), MerkleError>
+    where
+        T: AsBytes,
+    {
+        for value in values {
+            self.try_push(value)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the value of the leaf at `position` and recomputes only its
+    /// ancestors. Returns the old and the new root hash.
+    pub fn update<T>(&mut self, position: usize, value: &T) -> (Output<H>, Output<H>)
+    where
+        T: AsBytes,
+    {
+        self.try_update(position, value)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_update<T>(
+        &mut self,
+        position: usize,
+        value: &T,
+    ) -> Result<(Output<H>, Output<H>), MerkleError>
+    where
+        T: AsBytes,
+    {
+        let leaf = self.mode.hash_leaf(position, value, &mut self.hasher)?;
+        self.try_update_leaf_hash(position, &leaf)
+    }
+
+    /// Same as `update`, but takes the already hashed leaf.
+    pub fn update_leaf_hash(&mut self, position: usize, leaf: &[u8]) -> (Output<H>, Output<H>) {
+        self.try_update_leaf_hash(position, leaf)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_update_leaf_hash(
+        &mut self,
+        position: usize,
+        leaf: &[u8],
+    ) -> Result<(Output<H>, Output<H>), MerkleError> {
+        self.check_position(position)?;
+        check_leaf_hash_length::<H>(position, leaf)?;
+
+        let old_root = self.root.clone();
+        self.rehash_path(position, Output::<H>::clone_from_slice(leaf))?;
+
+        Ok((old_root, self.root.clone()))
+    }
+
+    /// Puts `leaf` at `position` along with its recomputed ancestors, reading
+    /// only their siblings from the store. The whole path is put at once, so
+    /// that neither the store nor the root change if any of it fails.
+    fn rehash_path(&mut self, position: usize, leaf: Output<H>) -> Result<(), MerkleError> {
+        let widths = level_widths(self.count_leaves);
+        let mut path = Vec::with_capacity(widths.len());
+        let mut index = position;
+        let mut node = leaf;
+        for (level, &width) in widths[..widths.len() - 1].iter().enumerate() {
+            let sibling = index ^ 1;
+            let parent = if sibling >= width {
+                self.mode.hash_lone_node(&node, &mut self.hasher)?
+            } else if index & 1 == 0 {
+                let right = self.try_node(level, sibling)?;
+                self.mode.hash_node(&node, &right, &mut self.hasher)?
+            } else {
+                let left = self.try_node(level, sibling)?;
+                self.mode.hash_node(&left, &node, &mut self.hasher)?
+            };
+            path.push((level, index, mem::replace(&mut node, parent)));
+            index /= 2;
+        }
+        path.push((widths.len() - 1, index, node.clone()));
+        self.store.put_nodes(path)?;
+        self.root = node;
+        Ok(())
+    }
+
+    pub fn root_hash(&self) -> &[u8] {
+        &self.root
+    }
+
+    pub fn root_hash_str(&self) -> String {
+        Hash::from(self.root.as_slice()).to_string()
+    }
+
+    /// Returns the audit path of the leaf at `position`: the sibling hashes
+    /// needed to recompute the root from that leaf alone.
+    pub fn prove(&self, position: usize) -> InclusionProof {
+        self.try_prove(position).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_prove(&self, position: usize) -> Result<InclusionProof, MerkleError> {
+        self.check_position(position)?;
+
+        Ok(InclusionProof::new(
+            self.mode,
+            self.count_leaves,
+            self.audit_path(0, position)?,
+        ))
+    }
+
+    /// Returns a proof that this tree extends its own first `old_size` leaves,
+    /// i.e. that the tree with `old_size` leaves was only appended to.
+    ///
+    /// Every subtree of the old tree that holds a power of two leaves is also
+    /// a node of this tree, so the proof is the audit path, within this tree,
+    /// of the largest such subtree containing the last old leaf. That subtree
+    /// is sent along unless it is the old root itself.
+    pub fn consistency_proof(&self, old_size: usize) -> ConsistencyProof {
+        self.try_consistency_proof(old_size)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_consistency_proof(&self, old_size: usize) -> Result<ConsistencyProof, MerkleError> {
+        if old_size > self.count_leaves {
+            return Err(MerkleError::OldSizeOutOfRange {
+                old_size,
+                count_leaves: self.count_leaves,
+            });
+        }
+
+        if old_size == 0 || old_size == self.count_leaves {
+            return Ok(ConsistencyProof::new(self.mode, Vec::new()));
+        }
+
+        let level = old_size.trailing_zeros() as usize;
+        let index = (old_size >> level) - 1;
+        let mut hashes = Vec::new();
+        if !old_size.is_power_of_two() {
+            hashes.push(self.try_node(level, index)?.as_slice().into());
+        }
+        hashes.extend(self.audit_path(level, index)?);
+
+        Ok(ConsistencyProof::new(self.mode, hashes))
+    }
+
+    /// Returns a single proof for all leaves at `positions`. Sibling hashes
+    /// shared between their audit paths, or computable from the proven
+    /// leaves themselves, are included only once or not at all.
+    pub fn prove_many(&self, positions: &[usize]) -> MultiProof {
+        self.try_prove_many(positions)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_prove_many(&self, positions: &[usize]) -> Result<MultiProof, MerkleError> {
+        for &position in positions {
+            self.check_position(position)?;
+        }
+
+        let mut known = positions.to_vec();
+        known.sort_unstable();
+        known.dedup();
+
+        let widths = level_widths(self.count_leaves);
+        let mut hashes = Vec::new();
+        for (level, &width) in widths[..widths.len() - 1].iter().enumerate() {
+            let mut parents = Vec::with_capacity(known.len());
+            let mut i = 0;
+            while i < known.len() {
+                let sibling = known[i] ^ 1;
+                if i + 1 < known.len() && known[i + 1] == sibling {
+                    i += 1;
+                } else if sibling < width {
+                    hashes.push(self.try_node(level, sibling)?.as_slice().into());
+                }
+                parents.push(known[i] / 2);
+                i += 1;
+            }
+            known = parents;
+        }
+
+        Ok(MultiProof::new(self.mode, self.count_leaves, hashes))
+    }
+
+    /// Returns the sibling hashes on the way from the node at `index` of
+    /// `level` up to the root. Lone nodes, which are paired with themselves,
+    /// contribute nothing.
+    fn audit_path(&self, level: usize, index: usize) -> Result<Vec<Hash>, MerkleError> {
+        let widths = level_widths(self.count_leaves);
+        let depth = widths.len() - 1;
+        let mut path = Vec::with_capacity(depth - level);
+        let mut index = index;
+        for (level, &width) in widths[..depth].iter().enumerate().skip(level) {
+            let sibling = index ^ 1;
+            if sibling < width {
+                path.push(self.try_node(level, sibling)?.as_slice().into());
+            }
+            index /= 2;
+        }
+        Ok(path)
+    }
+
+    /// Returns the node at `index` of `level`, counting levels from the
+    /// leaves up.
+    pub(crate) fn node(&self, level: usize, index: usize) -> Output<H> {
+        self.try_node(level, index)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub(crate) fn try_node(&self, level: usize, index: usize) -> Result<Output<H>, MerkleError> {
+        self.store
+            .get(level, index)?
+            .ok_or(MerkleError::Malformed("node missing from the store"))
+    }
+
+    pub fn verify<T>(&mut self, position: usize, value: &T) -> bool
+    where
+        T: AsBytes,
+    {
+        self.try_verify(position, value)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_verify<T>(&mut self, position: usize, value: &T) -> Result<bool, MerkleError>
+    where
+        T: AsBytes,
+    {
+        self.check_position(position)?;
+
+        let leaf = self.mode.hash_leaf(position, value, &mut self.hasher)?;
+        Ok(ct_eq(&self.try_node(0, position)?, &leaf))
+    }
+
+    fn check_position(&self, position: usize) -> Result<(), MerkleError> {
+        if position < self.count_leaves {
+            Ok(())
+        } else {
+            Err(MerkleError::PositionOutOfRange {
+                position,
+                count_leaves: self.count_leaves,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::hasher::{hash_internal_node, hash_leaf};
+    use super::super::{DefaultHasher, Hash, MerkleTree, TreeMode};
+    use super::{level_widths, BATCH_LEN};
+
+    #[test]
+    fn test_root_children_have_the_same_hash_if_blocks_were_the_same() {
+        let block = "Hello World";
+        let t: MerkleTree = MerkleTree::build(&[block, block, block, block, block]);
+
+        assert_eq!(t.node(2, 0), t.node(2, 1));
+    }
+
+    #[test]
+    fn test_root_childen_have_the_different_hash_if_blocks_were_the_different() {
+        let block1 = "Hello World";
+        let block2 = "Bye Bye";
+        let t: MerkleTree = MerkleTree::build(&[block1, block1, block2, block2]);
+
+        assert_ne!(t.node(1, 0), t.node(1, 1));
+    }
+
+    #[test]
+    fn test_pushing_values_matches_a_full_rebuild() {
+        let values: Vec<String> = (0..33).map(|i| i.to_string()).collect();
+        let mut t: MerkleTree = MerkleTree::build(&values[..2]);
+        for count in 3..values.len() + 1 {
+            t.push(&values[count - 1]);
+            let rebuilt: MerkleTree = MerkleTree::build(&values[..count]);
+
+            assert_eq!(t.root_hash(), rebuilt.root_hash());
+            assert_eq!(t.store(), rebuilt.store());
+        }
+    }
+
+    #[test]
+    fn test_extending_a_tree_stores_the_new_nodes() {
+        let values = ["a", "b", "c", "d", "e", "f"];
+        let mut t: MerkleTree = MerkleTree::build(&values[..4]);
+        t.extend(&values[4..]);
+
+        let widths: Vec<usize> = (0..4).map(|level| t.store().level(level).len()).collect();
+        assert_eq!(widths, [6, 3, 2, 1]);
+        assert_eq!(
+            t.root_hash(),
+            MerkleTree::<DefaultHasher>::build(&values).root_hash()
+        );
+    }
+
+    #[test]
+    fn test_updating_a_leaf_matches_a_full_rebuild() {
+        let mut values = vec!["a", "b", "c", "d", "e", "f", "g"];
+        let mut t: MerkleTree = MerkleTree::build(&values);
+        for position in 0..values.len() {
+            values[position] = "z";
+            let (old_root, new_root) = t.update(position, &"z");
+            let rebuilt: MerkleTree = MerkleTree::build(&values);
+
+            assert_ne!(old_root, new_root);
+            assert_eq!(new_root.as_slice(), rebuilt.root_hash());
+            assert_eq!(t.store(), rebuilt.store());
+        }
+    }
+
+    #[test]
+    fn test_pushing_onto_an_empty_tree_matches_a_full_rebuild() {
+        let values = ["a", "b", "c"];
+        let mut t: MerkleTree = MerkleTree::build::<&str>(&[]);
+        for count in 1..values.len() + 1 {
+            t.push(&values[count - 1]);
+            let rebuilt: MerkleTree = MerkleTree::build(&values[..count]);
+
+            assert_eq!(t.store(), rebuilt.store());
+        }
+    }
+
+    /// Merkle Tree Hash as defined recursively in RFC 6962, section 2.1.
+    fn rfc6962_root(leaves: &[Hash], hasher: &mut DefaultHasher) -> Hash {
+        if leaves.len() == 1 {
+            return leaves[0].clone();
+        }
+        let k = leaves.len().next_power_of_two() / 2;
+        let left = rfc6962_root(&leaves[..k], hasher);
+        let right = rfc6962_root(&leaves[k..], hasher);
+        hash_internal_node(&left, Some(&right), hasher)
+            .as_slice()
+            .into()
+    }
+
+    #[test]
+    fn test_rfc6962_mode_builds_the_rfc6962_tree() {
+        let mut hasher = DefaultHasher::new();
+        let values: Vec<String> = (0..40).map(|i| i.to_string()).collect();
+        let leaves: Vec<Hash> = values
+            .iter()
+            .map(|v| hash_leaf(v, &mut hasher).as_slice().into())
+            .collect();
+        for count in 1..values.len() + 1 {
+            let t: MerkleTree = MerkleTree::build_with_mode(
+                &values[..count],
+                DefaultHasher::new(),
+                TreeMode::RFC6962,
+            );
+
+            assert_eq!(
+                t.root_hash(),
+                rfc6962_root(&leaves[..count], &mut hasher).as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_trees_of_several_batches_are_built_whole() {
+        let mut hasher = DefaultHasher::new();
+        let values: Vec<String> = (0..2 * BATCH_LEN + 3).map(|i| i.to_string()).collect();
+        let leaves: Vec<Hash> = values
+            .iter()
+            .map(|v| hash_leaf(v, &mut hasher).as_slice().into())
+            .collect();
+        let t: MerkleTree =
+            MerkleTree::build_with_mode(&values, DefaultHasher::new(), TreeMode::RFC6962);
+        assert_eq!(t.root_hash(), rfc6962_root(&leaves, &mut hasher).as_bytes());
+        let widths: Vec<usize> = (0..20).map(|level| t.store().level(level).len()).collect();
+        let mut expected = level_widths(values.len());
+        expected.resize(20, 0);
+        assert_eq!(widths, expected);
+    }
+
+    #[test]
+    fn test_push_and_update_match_a_full_rebuild_in_every_mode() {
+        for &mode in [TreeMode::DEFAULT, TreeMode::RFC6962, TreeMode::BITCOIN].iter() {
+            let mut values: Vec<String> = (0..19).map(|i| i.to_string()).collect();
+            let mut t: MerkleTree =
+                MerkleTree::build_with_mode(&values[..0], DefaultHasher::new(), mode);
+            for count in 1..values.len() + 1 {
+                t.push(&values[count - 1]);
+                let rebuilt: MerkleTree =
+                    MerkleTree::build_with_mode(&values[..count], DefaultHasher::new(), mode);
+                assert_eq!(t.store(), rebuilt.store());
+            }
+
+            values[12] = "z".to_string();
+            t.update(12, &"z");
+            let rebuilt: MerkleTree =
+                MerkleTree::build_with_mode(&values, DefaultHasher::new(), mode);
+            assert_eq!(t.store(), rebuilt.store());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_parallel_build_matches_the_sequential_build() {
+        let values: Vec<String> = (0..3000).map(|i| i.to_string()).collect();
+        for &mode in [TreeMode::DEFAULT, TreeMode::RFC6962, TreeMode::BITCOIN].iter() {
+            for &count in [0, 1, 2, 5, 1024, 1025, 2049, 3000].iter() {
+                let sequential: MerkleTree =
+                    MerkleTree::build_with_mode(&values[..count], DefaultHasher::new(), mode);
+                let parallel: MerkleTree =
+                    MerkleTree::par_build_with_mode(&values[..count], DefaultHasher::new(), mode);
+                assert_eq!(parallel.store(), sequential.store());
+            }
+        }
+
+        let chunks = [vec![0u8; 1024], vec![0u8; 2048]];
+        let chunks: Vec<&[u8]> = chunks.iter().map(Vec::as_slice).collect();
+        let parallel =
+            MerkleTree::try_par_build_with_mode(&chunks, DefaultHasher::new(), TreeMode::BLAKE3);
+        assert_eq!(
+            parallel.unwrap_err(),
+            super::super::MerkleError::ChunkLengthOutOfRange {
+                position: 1,
+                received: 2048
+            }
+        );
+    }
+}
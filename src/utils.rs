@@ -1,12 +0,0 @@
-/// Returns next closest power of 2.
-pub fn next_power_of_2(n: usize) -> usize {
-    let mut v = n;
-    v -= 1;
-    v |= v >> 1;
-    v |= v >> 2;
-    v |= v >> 4;
-    v |= v >> 8;
-    v |= v >> 16;
-    v += 1;
-    v
-}
\ No newline at end of file
@@ -0,0 +1,138 @@
+// Trees over the RustCrypto hash functions, checked against known answers
+use digest::{FixedOutput, HashMarker, Output, OutputSizeUser, Reset, Update};
+use merkletree::{DefaultHasher, Digest, FixedOutputReset, Hash, MerkleTree, TreeMode};
+use sha2::Sha256;
+
+/// Checks `H` against the known answers for "" (the empty tree) and "abc"
+/// (a single leaf without domain separation), then checks that proofs of
+/// a larger tree verify with it.
+fn check_known_answers<H>(empty: &str, abc: &str)
+where
+    H: Digest + FixedOutputReset,
+{
+    let t: MerkleTree<H> = MerkleTree::build_with_hasher(&[] as &[&str], H::new());
+    assert_eq!(Hash::from(t.root_hash()).to_string(), empty);
+
+    let t: MerkleTree<H> = MerkleTree::build_with_mode(&["abc"], H::new(), TreeMode::BITCOIN);
+    assert_eq!(Hash::from(t.root_hash()).to_string(), abc);
+
+    let values: Vec<String> = (0..11).map(|i| i.to_string()).collect();
+    let t: MerkleTree<H> = MerkleTree::build_with_hasher(&values, H::new());
+    assert_eq!(t.root_hash().len(), <H as Digest>::output_size());
+    for (position, value) in values.iter().enumerate() {
+        assert!(t.prove(position).verify(
+            t.root_hash(),
+            values.len(),
+            position,
+            value,
+            t.mode(),
+            &mut H::new()
+        ));
+    }
+}
+
+#[test]
+fn test_sha2_known_answers() {
+    check_known_answers::<DefaultHasher>(
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+    );
+    check_known_answers::<sha2::Sha512_256>(
+        "c672b8d1ef56ed28ab87c3622c5114069bdd3ad7b8f9737498d0c01ecef0967a",
+        "53048e2681941ef99b2e29b76b4c7dabe4c2d0c634fc6d46e0e2f13107e7af23",
+    );
+}
+
+#[test]
+fn test_sha3_known_answers() {
+    check_known_answers::<sha3::Sha3_256>(
+        "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a",
+        "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532",
+    );
+    check_known_answers::<sha3::Keccak256>(
+        "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470",
+        "4e03657aea45a94fc7d47ba826c8d667c0d1e6e33a64a036ec44f58fa12d6c45",
+    );
+}
+
+#[test]
+fn test_blake2_known_answers() {
+    check_known_answers::<blake2::Blake2b512>(
+        "786a02f742015903c6c6fd852552d272912f4740e15847618a86e217f71f5419\
+         d25e1031afee585313896444934eb04b903a685b1448b755d56f701afe9be2ce",
+        "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+         7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923",
+    );
+    check_known_answers::<blake2::Blake2s256>(
+        "69217a3079908094e11121d042354a7c1f55b6482ca1a51e1b250dfd1ed0eef9",
+        "508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982",
+    );
+}
+
+/// SHA-256 of a key followed by the input: a hasher set up with something
+/// that resetting it keeps but `Keyed::default()` does not have.
+#[derive(Clone, Default)]
+struct Keyed {
+    key: Vec<u8>,
+    inner: Sha256,
+}
+
+impl Keyed {
+    fn new(key: &[u8]) -> Keyed {
+        Keyed {
+            key: key.to_vec(),
+            inner: Sha256::new_with_prefix(key),
+        }
+    }
+}
+
+impl HashMarker for Keyed {}
+
+impl OutputSizeUser for Keyed {
+    type OutputSize = <Sha256 as OutputSizeUser>::OutputSize;
+}
+
+impl Update for Keyed {
+    fn update(&mut self, data: &[u8]) {
+        Update::update(&mut self.inner, data)
+    }
+}
+
+impl FixedOutput for Keyed {
+    fn finalize_into(self, out: &mut Output<Self>) {
+        FixedOutput::finalize_into(self.inner, out)
+    }
+}
+
+impl Reset for Keyed {
+    fn reset(&mut self) {
+        self.inner = Sha256::new_with_prefix(&self.key);
+    }
+}
+
+impl FixedOutputReset for Keyed {
+    fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+        FixedOutput::finalize_into(self.inner.clone(), out);
+        Reset::reset(self);
+    }
+}
+
+#[test]
+fn test_hashers_are_reset_in_place_between_hashes() {
+    let values = ["a", "b", "c"];
+    let mut hasher = Keyed::new(b"key");
+    Update::update(&mut hasher, b"leftover");
+    let t = MerkleTree::build_with_hasher(&values, hasher);
+    let keyed = MerkleTree::build_with_hasher(&values, Keyed::new(b"key"));
+    assert_eq!(t.root_hash(), keyed.root_hash());
+
+    let leaf = Sha256::new_with_prefix(b"key")
+        .chain_update([0])
+        .chain_update(b"a")
+        .finalize();
+    assert_eq!(keyed.leaves()[0], leaf);
+    assert_ne!(
+        keyed.root_hash(),
+        MerkleTree::<DefaultHasher>::build(&values).root_hash()
+    );
+}
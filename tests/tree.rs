@@ -0,0 +1,136 @@
+// Building, updating and proving trees through the public API
+use merkletree::{DefaultHasher, MerkleError, MerkleTree, TreeMode};
+
+#[test]
+fn test_build_with_0_values() {
+    let _t: MerkleTree = MerkleTree::build::<String>(&[]);
+}
+
+#[test]
+fn test_build_with_odd_number_of_values() {
+    let block = "Hello World";
+    let _t: MerkleTree = MerkleTree::build(&[block, block, block]);
+}
+
+#[test]
+fn test_root_hash_stays_the_same_if_data_hasnt_been_change() {
+    let block = "Hello World";
+    let t: MerkleTree = MerkleTree::build(&[block, block]);
+
+    assert_eq!(
+        "c9978dc3e2d729207ca4c012de993423f19e7bf02161f7f95cdbf28d1b57b88a",
+        t.root_hash_str()
+    );
+}
+
+#[test]
+fn test_building_a_tree_from_existing_tree() {
+    let block = "Hello World";
+    let existing_tree: MerkleTree = MerkleTree::build(&[block, block]);
+
+    let new_tree: MerkleTree = MerkleTree::build_from_leaves(existing_tree.leaves());
+
+    assert_eq!(new_tree.root_hash_str(), existing_tree.root_hash_str());
+    assert_eq!(new_tree.leaves().len(), existing_tree.leaves().len());
+    assert_eq!(new_tree.leaves(), existing_tree.leaves());
+}
+
+#[test]
+fn test_updating_a_leaf_with_the_same_value_keeps_the_root() {
+    let mut t: MerkleTree = MerkleTree::build(&["a", "b", "c"]);
+    let leaf = t.leaves()[1];
+    let (old_root, new_root) = t.update_leaf_hash(1, &leaf);
+
+    assert_eq!(old_root, new_root);
+}
+
+#[test]
+fn test_fallible_constructors_report_errors_instead_of_panicking() {
+    let leaf = vec![0u8; 32];
+
+    assert_eq!(
+        MerkleTree::<DefaultHasher>::try_build(&["a"])
+            .unwrap()
+            .leaves()
+            .len(),
+        1
+    );
+    assert_eq!(
+        MerkleTree::<DefaultHasher>::try_build_from_leaves(&[leaf.clone(), vec![0u8; 31]])
+            .unwrap_err(),
+        MerkleError::LeafHashLengthMismatch {
+            position: 1,
+            expected: 32,
+            received: 31
+        }
+    );
+    assert!(MerkleTree::<DefaultHasher>::try_build_from_leaves(&[leaf.clone(), leaf]).is_ok());
+}
+
+#[test]
+fn test_fallible_accessors_report_positions_out_of_range() {
+    let mut t: MerkleTree = MerkleTree::build(&["a", "b", "c"]);
+    let out_of_range = MerkleError::PositionOutOfRange {
+        position: 3,
+        count_leaves: 3,
+    };
+
+    assert_eq!(t.try_verify(3, &"a").unwrap_err(), out_of_range);
+    assert_eq!(t.try_prove(3).unwrap_err(), out_of_range);
+    assert_eq!(t.try_prove_many(&[0, 3]).unwrap_err(), out_of_range);
+    assert_eq!(t.try_update(3, &"a").unwrap_err(), out_of_range);
+    assert_eq!(
+        t.try_consistency_proof(4).unwrap_err(),
+        MerkleError::OldSizeOutOfRange {
+            old_size: 4,
+            count_leaves: 3
+        }
+    );
+    assert_eq!(t.try_verify(2, &"c"), Ok(true));
+}
+
+#[test]
+fn test_empty_tree_has_the_hash_of_the_empty_string_as_root() {
+    let t: MerkleTree = MerkleTree::build::<String>(&[]);
+
+    assert_eq!(
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        t.root_hash_str()
+    );
+    assert!(t.leaves().is_empty());
+    assert!(t.try_prove(0).is_err());
+}
+
+#[test]
+fn test_single_leaf_tree_has_the_leaf_as_root() {
+    let t: MerkleTree = MerkleTree::build(&["Hello World"]);
+
+    assert_eq!(t.leaves().len(), 1);
+    assert_eq!(t.root_hash(), t.leaves()[0].as_slice());
+    assert!(t.prove(0).path().is_empty());
+    let mode = TreeMode::DEFAULT;
+    assert!(t.prove(0).verify(
+        t.root_hash(),
+        1,
+        0,
+        &"Hello World",
+        mode,
+        &mut DefaultHasher::new()
+    ));
+}
+
+#[test]
+fn test_duplicating_odd_nodes_cannot_tell_a_repeated_last_leaf_apart() {
+    let duplicate: MerkleTree = MerkleTree::build(&["a", "b", "c"]);
+    let repeated: MerkleTree = MerkleTree::build(&["a", "b", "c", "c"]);
+    let promote: MerkleTree =
+        MerkleTree::build_with_mode(&["a", "b", "c"], DefaultHasher::new(), TreeMode::RFC6962);
+    let promote_repeated: MerkleTree = MerkleTree::build_with_mode(
+        &["a", "b", "c", "c"],
+        DefaultHasher::new(),
+        TreeMode::RFC6962,
+    );
+
+    assert_eq!(duplicate.root_hash(), repeated.root_hash());
+    assert_ne!(promote.root_hash(), promote_repeated.root_hash());
+}